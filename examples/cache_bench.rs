@@ -0,0 +1,108 @@
+// How long it takes to read the instance cache in each encoding, which is
+// most of ashuf's startup when the cache is fresh.
+//
+//   cargo run --release --example cache_bench -- -n 5000
+//
+// The instances are made up, but shaped like a real account's: a handful of
+// tags each, and addresses spread over a few subnets.  With -f they're also
+// written out as a fixture for `aal -f` / `ashuf -f`.
+extern crate docopt;
+extern crate raal;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs::{File, create_dir_all, metadata};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use docopt::Docopt;
+
+use raal::cache_files::ALL_ENCODINGS;
+use raal::cache::{CacheKey, CachedResource, read_saved, write_saved_as};
+use raal::ec2_instances::AshufInfo;
+
+const USAGE: &'static str = "
+Time reading the instance cache in each encoding
+
+Usage:
+  cache_bench [-n <instances>] [-r <rounds>] [-d <bench_dir>] [-f <fixture>]
+  cache_bench (-h | --help)
+
+Options:
+  -h --help                 Show this help screen
+  -n <instances>            How many instances to cache [default: 5000]
+  -r <rounds>               How many times to read each cache [default: 20]
+  -d <bench_dir>            Where to write the caches [default: /tmp/raal_cache_bench]
+  -f <fixture>              Also write the instances here, as an aal fixture
+";
+
+const ACCOUNT: &'static str = "123456789012";
+const REGION: &'static str = "us-east-1";
+
+fn made_up_instances(count: usize) -> Vec<AshufInfo> {
+    let tiers = ["web", "api", "worker", "db", "cache", "batch"];
+    (0..count)
+        .map(|n| {
+            let tier = tiers[n % tiers.len()];
+            let mut tags = HashMap::new();
+            tags.insert("Name".to_string(), format!("{}-{:04}", tier, n));
+            tags.insert("Tier".to_string(), tier.to_string());
+            tags.insert("Environment".to_string(), "production".to_string());
+            tags.insert("Owner".to_string(), format!("team-{}", n % 17));
+            tags.insert("aws:autoscaling:groupName".to_string(), format!("{}-asg", tier));
+            AshufInfo {
+                instance_id: format!("i-{:017x}", n * 7919),
+                private_ip_addresses: vec![format!("10.{}.{}.{}", n % 4, (n / 250) % 256, n % 250 + 1)],
+                public_ip_addresses: Vec::new(),
+                state_name: "running".to_string(),
+                launch_time: "2019-06-01T12:00:00.000Z".to_string(),
+                availability_zone: format!("{}{}", REGION, ["a", "b", "c"][n % 3]),
+                image_ami: format!("ami-{:08x}", n % 5),
+                tags: tags,
+                region: REGION.to_string(),
+                asg_name: Some(format!("{}-asg", tier)),
+                lifecycle_state: Some("InService".to_string()),
+            }
+        })
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn main() {
+    let parsed_cmdline = Docopt::new(USAGE)
+        .and_then(|d| d.parse())
+        .unwrap_or_else(|e| e.exit());
+    let count: usize = parsed_cmdline.get_str("-n").parse().expect("-n should be a number");
+    let rounds: u32 = parsed_cmdline.get_str("-r").parse().expect("-r should be a number");
+    let bench_dir = parsed_cmdline.get_str("-d").to_string();
+
+    let instances = made_up_instances(count);
+    let fixture = parsed_cmdline.get_str("-f");
+    if !fixture.is_empty() {
+        let mut fixture_file = File::create(fixture).expect("Couldn't create the fixture");
+        fixture_file.write_all(serde_json::to_string_pretty(&instances).unwrap().as_bytes()).unwrap();
+        println!("Wrote {} instances to {}", count, fixture);
+    }
+
+    let key = CacheKey::new(&ACCOUNT.to_string(), &REGION.to_string(), AshufInfo::API);
+    println!("{:>8} {:>12} {:>12}", "encoding", "bytes", "read ms");
+    for encoding in ALL_ENCODINGS.iter() {
+        // Each encoding gets its own dir, since writing one removes the others
+        let cache_dir = format!("{}/{}", bench_dir, encoding.extension());
+        create_dir_all(&cache_dir).expect("Couldn't create the bench dir");
+        let pathname = write_saved_as(&cache_dir, &key, &instances, *encoding)
+            .expect("Couldn't write the cache");
+        let size = metadata(pathname).map(|m| m.len()).unwrap_or(0);
+
+        let started = Instant::now();
+        for _ in 0..rounds {
+            let envelope = read_saved::<AshufInfo>(&cache_dir, &key)
+                .expect("Couldn't read the cache back");
+            assert!(envelope.data.len() == count);
+        }
+        let per_read = millis(started.elapsed()) / rounds as f64;
+        println!("{:>8} {:>12} {:>12.2}", encoding.extension(), size, per_read);
+    }
+}
@@ -1,13 +1,34 @@
 // #[macro_use] extern crate lazy_static;
+extern crate chrono;
 extern crate docopt;
 extern crate raal;
 extern crate serde_json;
 extern crate shellexpand;
 
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use chrono::prelude::*;
 use docopt::Docopt;
 
-use raal::ec2_instances::{AshufInfo, read_without_cache, read_via_cache, instances_matching_regex};
-use raal::config::read_config;
+use raal::cache;
+use raal::cache::{CacheScope, CachePolicy, GLOBAL_REGION};
+use raal::ec2_instances::{AshufInfo, ASG_TAG, InstanceProvider, Ec2Provider, FixtureProvider, pattern_filters, poll_matching, read_matching, regions_matching};
+use raal::rds_instances;
+use raal::rds_instances::{DbInstanceProvider, RdsProvider};
+use raal::elasticache_nodes;
+use raal::elasticache_nodes::{CacheNodeProvider, ElastiCacheProvider};
+use raal::load_balancers;
+use raal::load_balancers::{LoadBalancerProvider, ElbProvider, healthy_only};
+use raal::ecs_tasks;
+use raal::ecs_tasks::{TaskProvider, EcsProvider};
+use raal::route53_records;
+use raal::route53_records::Route53Provider;
+use raal::daemon;
+use raal::daemon::DaemonQuery;
+use raal::config::{parse_duration, read_config};
+use raal::history::{InstanceChange, diff_instances};
 
 const USAGE: &'static str = "
 Query amazon for a random choice among some set of resources
@@ -15,7 +36,7 @@ Query amazon for a random choice among some set of resources
 Display matching resources as a JSON document.
 
 Usage:
-  aal [-c | --no-cache] [-e <env_name>] [-d <data_dir>] [-m <output_mode>]  [-n <name>] <pattern>
+  aal [-c | --no-cache] [--rds | --elasticache | --lb [--healthy] | --asg | --ecs | --dns] [-e <env_name>] [-d <data_dir>] [-m <output_mode>]  [-n <name>] [-f <fixture>] [-r <region_re>] [--endpoint-url=<url>] [--policy=<policy>] [--ttl=<ttl>] [--watch [--interval=<interval>]] <pattern>
   aal (-h | --help)
 
 Options:
@@ -25,8 +46,23 @@ Options:
   -e --env-name=<env_name>  The environment variable containing the name of this account [default: AWS_ACCOUNT_ID]
   -m --mode=<output_mode>   Output mode [default: json_ashuf_info]
   -n <name>                 Easy name for this environment [default: default]
+  -f --fixture=<fixture>    Read instances from a json file instead of the AWS API
+  -r --region=<region_re>   Only read the configured regions matching this regex
+  --endpoint-url=<url>      Send API calls to this endpoint instead of AWS
+  --policy=<policy>         Cache policy, ttl or cache_first (overrides the config)
+  --ttl=<ttl>               How old the cache may get, e.g. 600, 10m or 2h (overrides the config)
+  --rds                     List matching databases instead of instances
+  --elasticache             List matching cache cluster nodes instead of instances
+  --lb                      List matching load balancers and their targets instead of instances
+  --healthy                 With --lb, leave out targets that aren't healthy
+  --asg                     Match auto scaling group names instead of the Name and Tier tags
+  --ecs                     List ECS tasks matching service or task definition names
+  --dns                     List Route53 records matching the pattern
+  --watch                   Keep checking for matching instances, and print them as they come and go
+  --interval=<interval>     With --watch, time between checks, e.g. 30s or 2m [default: 30s]
 
 Output modes include: ip_private_line, json_ashuf_info, enum_name_tag
+With --watch: a line per change, or json_lines for one JSON object per change
 ";
 
 fn print_ip_private_line(results: Vec<AshufInfo>) {
@@ -43,6 +79,47 @@ fn print_json_ashuf_info(results: Vec<AshufInfo>) {
     println!("{}", serde_json::to_string_pretty(&results).expect("Couldn't serialize config"));
 }
 
+// Checking more often than this gets an account throttled
+const MIN_WATCH_INTERVAL: i64 = 10;
+
+fn print_changes(changes: &Vec<InstanceChange>, output_format: &str) {
+    for change in changes {
+        if output_format == "json_lines" {
+            println!("{}", serde_json::to_string(change).expect("Couldn't serialize change"));
+        } else {
+            println!("{} {}", Utc::now().format("%H:%M:%S"), change.describe());
+        }
+    }
+}
+
+// Poll for matching instances until interrupted, printing what changed
+// since the last poll.  Each poll goes through the same cache everyone
+// else uses (refreshed when it's older than the interval), or through raald
+// when it's running, so watching doesn't multiply the API calls, and the API
+// calls themselves back off when throttled.
+fn watch<F>(interval: i64, output_format: &str, mut poll: F)
+    where F: FnMut() -> Result<Vec<AshufInfo>, String>
+{
+    let mut previous = poll().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    eprintln!("Watching {} matching instances, every {}s", previous.len(), interval);
+    loop {
+        thread::sleep(Duration::from_secs(interval as u64));
+        // A region that couldn't be read isn't everything in it going away
+        let current = match poll() {
+            Ok(current) => current,
+            Err(err) => {
+                eprintln!("{}, waiting for the next check", err);
+                continue;
+            }
+        };
+        print_changes(&diff_instances(&previous, &current), output_format);
+        previous = current;
+    }
+}
+
 // fn print_enum_name_tag(results: Vec<AshufInfo>) {
 //     // prints a list of the names:addresses of instances, one pre line
 //     println!("When this works, sort and print a list, with numbers, of matches");
@@ -63,36 +140,167 @@ fn main() {
 
     let env_name = parsed_cmdline.get_str("-n");
     let bypass_cache = parsed_cmdline.get_bool("-c");
-    let cache_ttl = 3600;
     let data_dir = shellexpand::full(parsed_cmdline.get_str("-d"))
         .unwrap()
         .to_string();
     let config = read_config(&data_dir); 
-    let aws_id = config.environments
-        .get(&env_name.to_string())
-        .unwrap()
-        .account_id
-        .clone();
-    let aws_region = config.environments
-        .get(&env_name.to_string())
-        .unwrap()
-        .region
+    let mut environ = config.environment(env_name)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        })
         .clone();
+    let aws_id = environ.account_id.clone();
+    let aws_regions = regions_matching(
+        &parsed_cmdline.get_str("-r").to_string(),
+        environ.all_regions());
 
-    let all_instances = match bypass_cache {
-        true => {
-            if debug {
-                println!("Bypassing the cache");
-            }
-            read_without_cache(&data_dir, &aws_region, &aws_id)
-        },
-        false => read_via_cache(&data_dir, &aws_region, &aws_id, cache_ttl),
+    let endpoint_url = parsed_cmdline.get_str("--endpoint-url");
+    if !endpoint_url.is_empty() {
+        environ.endpoint_url = Some(endpoint_url.to_string());
+    }
+    let cache_settings = config.cache_settings(&environ,
+                                               Some(parsed_cmdline.get_str("--ttl")).filter(|ttl| !ttl.is_empty()),
+                                               Some(parsed_cmdline.get_str("--policy")).filter(|policy| !policy.is_empty()))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    let mut scope = CacheScope {
+        cache_dir: data_dir.clone(),
+        account: aws_id.clone(),
+        regions: aws_regions.clone(),
+        bypass_cache: bypass_cache,
+        settings: cache_settings.clone(),
     };
+
+    if parsed_cmdline.get_bool("--rds") {
+        let provider: Arc<dyn DbInstanceProvider> = Arc::new(RdsProvider::from_config(&environ));
+        let matches = cache::read_matching(&scope, &pattern, &vec!["identifier".to_string()], || {
+            rds_instances::read_regions(provider, &scope)
+        });
+        println!("{}", serde_json::to_string_pretty(&matches).expect("Couldn't serialize databases"));
+        return;
+    }
+    if parsed_cmdline.get_bool("--elasticache") {
+        let provider: Arc<dyn CacheNodeProvider> = Arc::new(ElastiCacheProvider::from_config(&environ));
+        let matches = cache::read_matching(&scope, &pattern, &vec!["cluster_id".to_string()], || {
+            elasticache_nodes::read_regions(provider, &scope)
+        });
+        println!("{}", serde_json::to_string_pretty(&matches).expect("Couldn't serialize cache nodes"));
+        return;
+    }
+    if parsed_cmdline.get_bool("--lb") {
+        let provider: Arc<dyn LoadBalancerProvider> = Arc::new(ElbProvider::from_config(&environ));
+        let mut matches = cache::read_matching(&scope, &pattern, &vec!["name".to_string()], || {
+            load_balancers::read_regions(provider, &scope)
+        });
+        if parsed_cmdline.get_bool("--healthy") {
+            matches = healthy_only(matches);
+        }
+        println!("{}", serde_json::to_string_pretty(&matches).expect("Couldn't serialize load balancers"));
+        return;
+    }
+    if parsed_cmdline.get_bool("--ecs") {
+        let provider: Arc<dyn TaskProvider> = Arc::new(EcsProvider::from_config(&environ));
+        let tags = vec!["service".to_string(), "task_definition".to_string()];
+        let matches = cache::read_matching(&scope, &pattern, &tags, || {
+            ecs_tasks::read_regions(provider, &scope)
+        });
+        println!("{}", serde_json::to_string_pretty(&matches).expect("Couldn't serialize tasks"));
+        return;
+    }
+    if parsed_cmdline.get_bool("--dns") {
+        let provider = Route53Provider::from_config(&environ);
+        let global_scope = CacheScope {
+            regions: vec![GLOBAL_REGION.to_string()],
+            ..scope.clone()
+        };
+        let matches = cache::read_matching(&global_scope, &pattern, &vec!["name".to_string()], || {
+            route53_records::read_records(&provider, &global_scope)
+        });
+        println!("{}", serde_json::to_string_pretty(&matches).expect("Couldn't serialize records"));
+        return;
+    }
+
+    let fixture = parsed_cmdline.get_str("-f");
+    let provider: Arc<dyn InstanceProvider> = if fixture.is_empty() {
+        Arc::new(Ec2Provider::from_config(&environ))
+    } else {
+        // A fixture isn't the account, so it stays out of the account's cache
+        scope.settings.policy = CachePolicy::Uncached;
+        Arc::new(FixtureProvider {
+            path: shellexpand::full(fixture).unwrap().to_string(),
+        })
+    };
+
     // These are the tags we'll filter on
-    let tags = vec!["Name".to_string(), "Tier".to_string()];
-    let matches = instances_matching_regex(pattern, tags, all_instances);
-    // let matched_json = serde_json::to_string_pretty(&matches).expect("Couldn't serialize config");
+    let tags = if parsed_cmdline.get_bool("--asg") {
+        vec![ASG_TAG.to_string()]
+    } else {
+        vec!["Name".to_string(), "Tier".to_string()]
+    };
+    // Without the cache, ask the API for only what could match
+    let filters = if bypass_cache {
+        if debug {
+            println!("Bypassing the cache");
+        }
+        pattern_filters(&pattern, &tags, false)
+    } else {
+        Vec::new()
+    };
     let output_format = parsed_cmdline.get_str("-m");
+    if parsed_cmdline.get_bool("--watch") {
+        let interval = match parse_duration(parsed_cmdline.get_str("--interval")) {
+            Ok(seconds) if seconds < MIN_WATCH_INTERVAL => {
+                eprintln!("Checking every {}s instead, to stay clear of throttling", MIN_WATCH_INTERVAL);
+                MIN_WATCH_INTERVAL
+            },
+            Ok(seconds) => seconds,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        };
+        // The cache is refreshed once it's older than the interval, which
+        // cache_first would never do
+        let mut watch_scope = scope.clone();
+        watch_scope.settings.ttl = interval;
+        if watch_scope.settings.policy == CachePolicy::CacheFirst {
+            watch_scope.settings.policy = CachePolicy::Ttl;
+        }
+        let daemon_query = DaemonQuery {
+            env_name: env_name.to_string(),
+            regions: aws_regions.clone(),
+            pattern: pattern.clone(),
+            tags: tags.clone(),
+        };
+        let use_daemon = scope.uses_cache();
+        watch(interval, output_format, || {
+            let daemon_matches = if use_daemon { daemon::query(&data_dir, &daemon_query) } else { None };
+            match daemon_matches {
+                Some(matches) => Ok(matches),
+                None => poll_matching(provider.clone(), &watch_scope, &filters, &pattern, &tags),
+            }
+        });
+        return;
+    }
+    // raald already has everything in memory, when it's running
+    let daemon_matches = if scope.uses_cache() {
+        daemon::query(&data_dir, &DaemonQuery {
+            env_name: env_name.to_string(),
+            regions: aws_regions.clone(),
+            pattern: pattern.clone(),
+            tags: tags.clone(),
+        })
+    } else {
+        None
+    };
+    let matches = match daemon_matches {
+        Some(matches) => matches,
+        None => read_matching(provider, &scope, &filters, &pattern, &tags),
+    };
+    // let matched_json = serde_json::to_string_pretty(&matches).expect("Couldn't serialize config");
 
     if output_format == "ip_private_line" {
         print_ip_private_line(matches);
@@ -0,0 +1,204 @@
+extern crate chrono;
+extern crate docopt;
+extern crate raal;
+extern crate regex;
+extern crate shellexpand;
+
+use std::process;
+use chrono::prelude::*;
+use docopt::Docopt;
+use regex::Regex;
+
+use raal::cache::{CachedResource, CacheSettings, CachePolicy, describe_age, list_snapshots};
+use raal::cache_admin::{CacheEntry, api_named, delete_entry, list_entries, refresh_entry, warm_environment};
+use raal::config::{Config, read_config};
+use raal::ec2_instances::AshufInfo;
+use raal::history::{diff_instances, snapshot_instances};
+
+const USAGE: &'static str = "
+Look after the cached resources in a data directory
+
+Usage:
+  acache list [-d <data_dir>]
+  acache refresh [-d <data_dir>] [-n <name>] [-r <region_re>] [-a <api>]
+  acache delete [-d <data_dir>] [-n <name>] [-r <region_re>] [-a <api>]
+  acache warm [-d <data_dir>] [-n <name>] [-a <api>]
+  acache history [-d <data_dir>] [-n <name>] [-r <region_re>]
+  acache diff [-d <data_dir>] [-n <name>] [-r <region_re>] [--from=<n>] [--to=<n>]
+  acache (-h | --help)
+
+Options:
+  -h --help                 Show this help screen
+  -d <data_dir>             Data directory with cached data and config [default: ~/.raal]
+  -n <name>                 Only this environment's caches (all of them otherwise)
+  -r --region=<region_re>   Only the regions matching this regex
+  -a --api=<api>            Only this API's caches.  warm reads ec2_instances unless told otherwise.
+  --from=<n>                The older instance list to compare: 0 is the current cache, 1 the one it replaced, and so on [default: 1]
+  --to=<n>                  The newer instance list to compare [default: 0]
+
+APIs are ec2_instances, rds_instances, elasticache_nodes, load_balancers,
+ecs_tasks and route53_records.  warm only refreshes caches that have expired,
+so it's cheap to put in a login script.
+
+Each refresh of ec2_instances keeps the cache it replaces, up to
+history_snapshots (5 unless configured) per account and region.  history
+lists them, and diff shows the instances launched, gone, or changed between
+two of them.
+";
+
+fn print_entries(entries: &Vec<CacheEntry>) {
+    println!("{:<14} {:<16} {:<18} {:<8} {:<26} {:<8} COUNT", "ACCOUNT", "REGION", "API", "ENCODING", "WRITTEN", "AGE");
+    for entry in entries {
+        let (written, age, count) = match entry.contents {
+            Ok((written_time, count)) => (written_time.to_rfc3339(),
+                                          describe_age(Utc::now().signed_duration_since(written_time)),
+                                          count.to_string()),
+            Err(ref err) => ("unreadable".to_string(), "-".to_string(), err.clone()),
+        };
+        println!("{:<14} {:<16} {:<18} {:<8} {:<26} {:<8} {}",
+                 entry.key.account, entry.key.region, entry.key.api, entry.encoding.extension(), written, age, count);
+    }
+}
+
+// The entries picked out by -n, -r and -a
+fn selected_entries(config: &Config, entries: Vec<CacheEntry>, env_name: &str, region_re: &str, api: &str) -> Vec<CacheEntry> {
+    let account = if env_name.is_empty() {
+        None
+    } else {
+        match config.environments.get(env_name) {
+            Some(environ) => Some(environ.account_id.clone()),
+            None => {
+                println!("There's no environment named {} in the config", env_name);
+                process::exit(1);
+            }
+        }
+    };
+    let region_re = Regex::new(region_re).unwrap_or_else(|err| {
+        println!("Couldn't use the region regex: {}", err);
+        process::exit(1);
+    });
+    entries.into_iter()
+        .filter(|entry| account.as_ref().is_none_or(|account| &entry.key.account == account))
+        .filter(|entry| region_re.is_match(&entry.key.region))
+        .filter(|entry| api.is_empty() || entry.key.api == api)
+        .collect()
+}
+
+fn print_history(data_dir: &String, entries: Vec<CacheEntry>) {
+    for entry in entries {
+        let snapshots = list_snapshots(data_dir, &entry.key).unwrap_or_else(|err| {
+            println!("Couldn't list the snapshots of {}: {}", entry.key, err);
+            Vec::new()
+        });
+        println!("{}", entry.key);
+        for index in 0..snapshots.len() + 1 {
+            match snapshot_instances(data_dir, &entry.key, index) {
+                Ok((written_time, instances)) => println!("  {:<3} {:<26} {:<8} {}", index, written_time.to_rfc3339(),
+                                                          describe_age(Utc::now().signed_duration_since(written_time)),
+                                                          instances.len()),
+                Err(err) => println!("  {:<3} unreadable: {}", index, err),
+            }
+        }
+    }
+}
+
+fn print_diffs(data_dir: &String, entries: Vec<CacheEntry>, from: usize, to: usize) {
+    for entry in entries {
+        let (before, after) = match (snapshot_instances(data_dir, &entry.key, from),
+                                     snapshot_instances(data_dir, &entry.key, to)) {
+            (Ok(before), Ok(after)) => (before, after),
+            (Err(err), _) | (_, Err(err)) => {
+                println!("{}: can't compare {} with {}: {}", entry.key, from, to, err);
+                continue;
+            }
+        };
+        println!("{}: {} -> {}", entry.key, before.0.to_rfc3339(), after.0.to_rfc3339());
+        let changes = diff_instances(&before.1, &after.1);
+        if changes.is_empty() {
+            println!("  no changes");
+        }
+        for change in changes {
+            println!("  {}", change.describe());
+        }
+    }
+}
+
+fn snapshot_index(parsed_cmdline: &docopt::ArgvMap, flag: &str) -> usize {
+    parsed_cmdline.get_str(flag).parse().unwrap_or_else(|_| {
+        println!("{} should be a number of snapshots back", flag);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let version = "0.1.0".to_owned();
+    let parsed_cmdline = Docopt::new(USAGE)
+        .and_then(|d| d.version(Some(version)).parse())
+        .unwrap_or_else(|e| e.exit());
+    let data_dir = shellexpand::full(parsed_cmdline.get_str("-d"))
+        .unwrap()
+        .to_string();
+    let env_name = parsed_cmdline.get_str("-n");
+    let api = parsed_cmdline.get_str("-a");
+    if !api.is_empty() && api_named(api).is_none() {
+        println!("{} isn't an API with a cache", api);
+        process::exit(1);
+    }
+    let entries = list_entries(&data_dir).unwrap_or_else(|err| {
+        println!("Couldn't read {}: {}", data_dir, err);
+        process::exit(1);
+    });
+
+    if parsed_cmdline.get_bool("list") {
+        print_entries(&entries);
+        return;
+    }
+
+    let config = read_config(&data_dir);
+    if parsed_cmdline.get_bool("history") {
+        print_history(&data_dir, selected_entries(&config, entries, env_name, parsed_cmdline.get_str("-r"), AshufInfo::API));
+    } else if parsed_cmdline.get_bool("diff") {
+        let from = snapshot_index(&parsed_cmdline, "--from");
+        let to = snapshot_index(&parsed_cmdline, "--to");
+        print_diffs(&data_dir, selected_entries(&config, entries, env_name, parsed_cmdline.get_str("-r"), AshufInfo::API), from, to);
+    } else if parsed_cmdline.get_bool("delete") {
+        for entry in selected_entries(&config, entries, env_name, parsed_cmdline.get_str("-r"), api) {
+            match delete_entry(&data_dir, &entry.key) {
+                Ok(()) => println!("Deleted {}", entry.key),
+                Err(err) => println!("Couldn't delete {}: {}", entry.key, err),
+            }
+        }
+    } else if parsed_cmdline.get_bool("refresh") {
+        for entry in selected_entries(&config, entries, env_name, parsed_cmdline.get_str("-r"), api) {
+            // The credentials and role come from the environment for this
+            // account.  One with its own endpoint isn't really the account.
+            match config.environments.values().find(|environ| environ.account_id == entry.key.account && environ.endpoint_url.is_none()) {
+                Some(environ) => match refresh_entry(&data_dir, environ, &entry.key, &config.cache_settings(environ, None, None).unwrap()) {
+                    Ok(found) => println!("Refreshed {}: {} found", entry.key, found),
+                    Err(err) => println!("Couldn't refresh {}: {}", entry.key, err),
+                },
+                None => println!("No environment in the config has account {}, skipping {}", entry.key.account, entry.key),
+            }
+        }
+    } else if parsed_cmdline.get_bool("warm") {
+        let api = api_named(api).unwrap_or(AshufInfo::API);
+        for (name, environ) in config.environments.iter() {
+            if !env_name.is_empty() && name != env_name {
+                continue;
+            }
+            let settings = config.cache_settings(environ, None, None).unwrap();
+            if settings.policy == CachePolicy::Uncached {
+                println!("{}: skipped, its endpoint isn't AWS", name);
+                continue;
+            }
+            let settings = CacheSettings {
+                policy: CachePolicy::Ttl,
+                ..settings
+            };
+            match warm_environment(&data_dir, environ, api, &settings) {
+                Ok(found) => println!("{}: {} {} found", name, found, api),
+                Err(err) => println!("{}: {}", name, err),
+            }
+        }
+    }
+}
@@ -4,13 +4,31 @@ extern crate raal;
 extern crate rand;
 extern crate shellexpand;
 
+use std::collections::{HashMap, HashSet};
+use std::process;
 use std::process::Command;
 use std::os::unix::process::CommandExt;
+use std::sync::Arc;
 use docopt::Docopt;
-use rand::{sample, thread_rng};
+use rand::{Rng, thread_rng};
 
-use raal::ec2_instances::{AshufInfo, read_without_cache, read_via_cache, instances_matching_regex, running_instances};
-use raal::config::read_config ;
+use raal::cache;
+use raal::cache::{CacheScope, CachePolicy};
+use raal::ec2_instances::{AshufInfo, ASG_TAG, InstanceProvider, Ec2Provider, FixtureProvider, pattern_filters, read_matching, read_regions, regions_matching, instances_matching_regex, instances_with_ids, instances_with_ips, in_service_instances, running_instances};
+use raal::rds_instances;
+use raal::rds_instances::{RdsInfo, DbInstanceProvider, RdsProvider, available_instances};
+use raal::elasticache_nodes;
+use raal::elasticache_nodes::{CacheNodeInfo, CacheNodeProvider, ElastiCacheProvider, available_nodes};
+use raal::load_balancers;
+use raal::load_balancers::{LoadBalancerProvider, ElbProvider, healthy_target_ids};
+use raal::ecs_tasks;
+use raal::ecs_tasks::{EcsTaskInfo, TaskProvider, EcsProvider, running_tasks};
+use raal::route53_records;
+use raal::route53_records::{Route53Provider, resolve_records, canonical_dns_name};
+use raal::launch::{fill_template, exec_args};
+use raal::daemon;
+use raal::daemon::DaemonQuery;
+use raal::config::{Config, read_config};
 
 const USAGE: &'static str = "
 Query amazon for a random choice among some set of resources
@@ -18,7 +36,7 @@ Query amazon for a random choice among some set of resources
 Display matching resources as a JSON document.
 
 Usage:
-  ashuf [-c] [-C] [-v] [-d <data_dir>] [-n <name>] <pattern> [<more_ssh_options>...]
+  ashuf [-c] [-C] [-v] [--rds | --elasticache | --lb | --asg | --ecs | --dns] [-d <data_dir>] [-n <name>] [-f <fixture>] [-r <region_re>] [--endpoint-url=<url>] [--policy=<policy>] [--ttl=<ttl>] <pattern> [<more_ssh_options>...]
   ashuf (-h | --help)
 
 Options:
@@ -28,16 +46,31 @@ Options:
   -s --ssh-command=<cmd>    Path to ssh or a wrapper [default: /usr/bin/ssh]
   -d <data_dir>             Data directory with cached data and config [default: ~/.raal]
   -n <name>                 Easy name for this environment [default: default]
+  -f --fixture=<fixture>    Read instances from a json file instead of the AWS API
+  -r --region=<region_re>   Only read the configured regions matching this regex
+  --endpoint-url=<url>      Send API calls to this endpoint instead of AWS
+  --policy=<policy>         Cache policy, ttl or cache_first (overrides the config)
+  --ttl=<ttl>               How old the cache may get, e.g. 600, 10m or 2h (overrides the config)
+  --rds                     Pick a database and start its client instead of ssh
+  --elasticache             Pick a cache cluster node and start its client instead of ssh
+  --lb                      Match load balancer names, and ssh to one of their healthy targets
+  --asg                     Match auto scaling group names instead of the Name and Tier tags
+  --ecs                     Match ECS service and task definition names, and ssh to the host
+                            of one of their tasks (running the configured ecs_exec client there)
+  --dns                     Match Route53 record names, and ssh to an instance they point at
 
 ";
 
-fn launch_ssh(ssh_path: String, more_ssh_options: Vec<String>, info: AshufInfo) {
+fn launch_ssh(ssh_path: String, more_ssh_options: Vec<String>, host: String, remote_command: Vec<String>) {
     let mut args = vec!["-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null" ];
 
     for arg in &more_ssh_options {
-        args.push(&arg);
+        args.push(arg);
+    }
+    args.push(&host);
+    for arg in &remote_command {
+        args.push(arg);
     }
-    args.push(&info.private_ip_addresses[0]);
     
     let could_not_exec = Command::new(ssh_path.clone())
         .args(args.clone())
@@ -45,6 +78,122 @@ fn launch_ssh(ssh_path: String, more_ssh_options: Vec<String>, info: AshufInfo)
     println!("Couldn't exec {} {:?} because {:?}", ssh_path, args, could_not_exec);
 }
 
+// Start the client command configured for an engine, with the
+// {placeholders} in its template filled in from values.
+fn launch_client(config: &Config, engine: &String, values: HashMap<String, String>, more_options: Vec<String>) {
+    let template = match config.client_command(engine) {
+        Some(template) => template,
+        None => {
+            println!("There's no client configured for the {} engine", engine);
+            return;
+        }
+    };
+    let mut args = fill_template(&template, &values);
+    args.extend(more_options);
+
+    println!("Command: {:?}", args);
+    let could_not_exec = exec_args(&args);
+    println!("Couldn't exec {:?} because {:?}", args, could_not_exec);
+}
+
+// Like launching ssh, but for a database: pick an available one at random and
+// start the client that's configured for its engine.
+fn shuf_rds(config: &Config, databases: Vec<RdsInfo>, pattern: String, more_options: Vec<String>, debug: bool) {
+    let tags = vec!["identifier".to_string()];
+    let matches = available_instances(instances_matching_regex(pattern, tags, databases));
+
+    let mut rng = thread_rng();
+    let sampled_database = rng.choose(&matches).cloned().into_iter().collect::<Vec<_>>();
+    if sampled_database.is_empty() {
+        println!("No databases matched your request, not doing anything");
+        return;
+    }
+    let db = &sampled_database[0];
+    if debug {
+        println!("{:?}", db);
+    }
+    println!("Identifier: {} Engine: {} Region: {}", db.identifier, db.engine, db.region);
+
+    let mut values = HashMap::new();
+    values.insert("host".to_string(), db.endpoint.clone());
+    values.insert("port".to_string(), db.port.to_string());
+    values.insert("user".to_string(), db.master_username.clone());
+    values.insert("dbname".to_string(), db.db_name.clone());
+    values.insert("identifier".to_string(), db.identifier.clone());
+    launch_client(config, &db.engine, values, more_options);
+}
+
+// The same for a node of an ElastiCache cluster
+fn shuf_cache_node(config: &Config, nodes: Vec<CacheNodeInfo>, pattern: String, more_options: Vec<String>, debug: bool) {
+    let tags = vec!["cluster_id".to_string()];
+    let matches = available_nodes(instances_matching_regex(pattern, tags, nodes));
+
+    let mut rng = thread_rng();
+    let sampled_node = rng.choose(&matches).cloned().into_iter().collect::<Vec<_>>();
+    if sampled_node.is_empty() {
+        println!("No cache nodes matched your request, not doing anything");
+        return;
+    }
+    let node = &sampled_node[0];
+    if debug {
+        println!("{:?}", node);
+    }
+    println!("Cluster: {} Node: {} Engine: {} Region: {}", node.cluster_id, node.node_id, node.engine, node.region);
+
+    let mut values = HashMap::new();
+    values.insert("host".to_string(), node.endpoint.clone());
+    values.insert("port".to_string(), node.port.to_string());
+    values.insert("cluster_id".to_string(), node.cluster_id.clone());
+    launch_client(config, &node.engine, values, more_options);
+}
+
+// Pick a random running task, and ssh to the instance it's on.  When an
+// `ecs_exec` client is configured (e.g. "docker exec -it {container_id} sh")
+// that's run on the host, with the task's first container filled in.
+fn shuf_ecs(config: &Config, tasks: Vec<EcsTaskInfo>, pattern: String, ssh_path: String, more_ssh_options: Vec<String>, debug: bool) {
+    let tags = vec!["service".to_string(), "task_definition".to_string()];
+    let matches = running_tasks(instances_matching_regex(pattern, tags, tasks));
+
+    let mut rng = thread_rng();
+    let sampled_task = rng.choose(&matches).cloned().into_iter().collect::<Vec<_>>();
+    if sampled_task.is_empty() {
+        println!("No tasks matched your request, not doing anything");
+        return;
+    }
+    let task = &sampled_task[0];
+    if debug {
+        println!("{:?}", task);
+    }
+    println!("Cluster: {} Service: {} Task definition: {} Host: {} Region: {}",
+             task.cluster, task.service, task.task_definition, task.private_ip, task.region);
+
+    let mut ssh_options = more_ssh_options;
+    let remote_command = match config.clients.get("ecs_exec") {
+        Some(template) => {
+            let mut values = HashMap::new();
+            if let Some(container) = task.containers.first() {
+                values.insert("container_id".to_string(), container.runtime_id.clone());
+                values.insert("container_name".to_string(), container.name.clone());
+            }
+            values.insert("task_arn".to_string(), task.task_arn.clone());
+            // An interactive exec on the host needs a tty
+            ssh_options.push("-t".to_string());
+            fill_template(template, &values)
+        },
+        None => Vec::new(),
+    };
+    launch_ssh(ssh_path, ssh_options, task.private_ip.clone(), remote_command);
+}
+
+
+// The instances behind these load balancer targets.  Targets in an
+// instance target group are instance ids, and those in an ip target group
+// are private addresses.
+fn target_instances(target_ids: &HashSet<String>, instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
+    let mut found = instances_with_ids(target_ids, instances.clone());
+    found.extend(instances_with_ips(target_ids, instances));
+    found
+}
 
 fn main() {
     let version = "0.1.0".to_owned();
@@ -59,45 +208,47 @@ fn main() {
     };
     let env_name = parsed_cmdline.get_str("-n");
     let bypass_cache = parsed_cmdline.get_bool("-c");
-    let cache_ttl = 3600;
     let data_dir = shellexpand::full(parsed_cmdline.get_str("-d"))
         .unwrap()
         .to_string();
     let config = read_config(&data_dir); 
-    let aws_id = config.environments
-        .get(&env_name.to_string())
-        .unwrap()
-        .account_id
-        .clone();
-    let aws_region = config.environments
-        .get(&env_name.to_string())
-        .unwrap()
-        .region
+    let mut environ = config.environment(env_name)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        })
         .clone();
-    
-    let all_instances = match bypass_cache {
-        true => {
-            if debug {
-                println!("Bypassing the cache");
-            }
-            read_without_cache(&data_dir, &aws_region, &aws_id)
-        },
-        false => read_via_cache(&data_dir, &aws_region, &aws_id, cache_ttl),
+    let aws_id = environ.account_id.clone();
+    let aws_regions = regions_matching(
+        &parsed_cmdline.get_str("-r").to_string(),
+        environ.all_regions());
+
+    let endpoint_url = parsed_cmdline.get_str("--endpoint-url");
+    if !endpoint_url.is_empty() {
+        environ.endpoint_url = Some(endpoint_url.to_string());
+    }
+    let cache_settings = config.cache_settings(&environ,
+                                               Some(parsed_cmdline.get_str("--ttl")).filter(|ttl| !ttl.is_empty()),
+                                               Some(parsed_cmdline.get_str("--policy")).filter(|policy| !policy.is_empty()))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    let mut scope = CacheScope {
+        cache_dir: data_dir.clone(),
+        account: aws_id.clone(),
+        regions: aws_regions.clone(),
+        bypass_cache: bypass_cache,
+        settings: cache_settings.clone(),
     };
-    // These are the tags we'll filter on
-    let tags = vec!["Name".to_string(), "Tier".to_string()];
-    let matches = instances_matching_regex(pattern, tags, all_instances);
-    let alive_matches = running_instances(matches);
+
     let ssh_path = parsed_cmdline.get_str("-s");
 
     // Allow the configured ssh options to be overridden
     let more_ssh_options = {
-        let mut conf_opts = match config.environments.get(&env_name.to_string()) {
-            Some(cf) => cf.ssh_options.clone(),
-            None => vec![]
-        };
+        let mut conf_opts = environ.ssh_options.clone();
 
-        if parsed_cmdline.get_vec("<more_ssh_options>").len() > 0 {
+        if !parsed_cmdline.get_vec("<more_ssh_options>").is_empty() {
             let mut extra_ssh_opts = parsed_cmdline.get_vec("<more_ssh_options>")
                 .into_iter()
                 .map(|c| c.to_string())
@@ -107,9 +258,115 @@ fn main() {
         conf_opts
     };
 
+    // Extra arguments go to the database or cache client in those modes
+    let more_client_options = parsed_cmdline.get_vec("<more_ssh_options>")
+        .into_iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>();
+    if parsed_cmdline.get_bool("--rds") {
+        let provider: Arc<dyn DbInstanceProvider> = Arc::new(RdsProvider::from_config(&environ));
+        let databases = cache::read_matching(&scope, &pattern, &vec!["identifier".to_string()], || {
+            rds_instances::read_regions(provider, &scope)
+        });
+        shuf_rds(&config, databases, pattern, more_client_options, debug);
+        return;
+    }
+    if parsed_cmdline.get_bool("--elasticache") {
+        let provider: Arc<dyn CacheNodeProvider> = Arc::new(ElastiCacheProvider::from_config(&environ));
+        let nodes = cache::read_matching(&scope, &pattern, &vec!["cluster_id".to_string()], || {
+            elasticache_nodes::read_regions(provider, &scope)
+        });
+        shuf_cache_node(&config, nodes, pattern, more_client_options, debug);
+        return;
+    }
+    if parsed_cmdline.get_bool("--ecs") {
+        let provider: Arc<dyn TaskProvider> = Arc::new(EcsProvider::from_config(&environ));
+        let tags = vec!["service".to_string(), "task_definition".to_string()];
+        let tasks = cache::read_matching(&scope, &pattern, &tags, || {
+            ecs_tasks::read_regions(provider, &scope)
+        });
+        shuf_ecs(&config, tasks, pattern, ssh_path.to_string(), more_ssh_options, debug);
+        return;
+    }
+
+    let fixture = parsed_cmdline.get_str("-f");
+    let provider: Arc<dyn InstanceProvider> = if fixture.is_empty() {
+        Arc::new(Ec2Provider::from_config(&environ))
+    } else {
+        // A fixture isn't the account, so it stays out of the account's cache
+        scope.settings.policy = CachePolicy::Uncached;
+        Arc::new(FixtureProvider {
+            path: shellexpand::full(fixture).unwrap().to_string(),
+        })
+    };
+    
+    // These are the tags we'll filter on
+    let tags = if parsed_cmdline.get_bool("--asg") {
+        vec![ASG_TAG.to_string()]
+    } else {
+        vec!["Name".to_string(), "Tier".to_string()]
+    };
+    let by_load_balancer = parsed_cmdline.get_bool("--lb");
+    let by_dns = parsed_cmdline.get_bool("--dns");
+    // Without the cache, ask the API for only what could match.  With --lb
+    // or --dns the pattern isn't for tags, so it can't be pushed down.
+    let filters = if bypass_cache && !by_load_balancer && !by_dns {
+        if debug {
+            println!("Bypassing the cache");
+        }
+        pattern_filters(&pattern, &tags, true)
+    } else {
+        Vec::new()
+    };
+    // raald already has everything in memory, when it's running
+    let daemon_matches = if scope.uses_cache() && !by_load_balancer && !by_dns {
+        daemon::query(&data_dir, &DaemonQuery {
+            env_name: env_name.to_string(),
+            regions: aws_regions.clone(),
+            pattern: pattern.clone(),
+            tags: tags.clone(),
+        })
+    } else {
+        None
+    };
+    let matches = if let Some(matches) = daemon_matches {
+        matches
+    } else if by_load_balancer {
+        let all_instances = read_regions(provider, &scope, &filters);
+        let lb_provider: Arc<dyn LoadBalancerProvider> = Arc::new(ElbProvider::from_config(&environ));
+        let lbs = load_balancers::read_regions(lb_provider, &scope);
+        let lb_matches = instances_matching_regex(pattern, vec!["name".to_string()], lbs);
+        target_instances(&healthy_target_ids(&lb_matches), all_instances)
+    } else if by_dns {
+        let all_instances = read_regions(provider, &scope, &filters);
+        let records = route53_records::read_records(&Route53Provider::from_config(&environ), &scope);
+        let record_matches = instances_matching_regex(pattern, vec!["name".to_string()], records.clone());
+        let (addresses, external_names) = resolve_records(&record_matches, &records);
+        // Names outside of our zones may be load balancers, whose healthy
+        // targets are instances too
+        let mut target_ids = HashSet::new();
+        if !external_names.is_empty() {
+            let lb_names = external_names.iter()
+                .map(canonical_dns_name)
+                .collect::<HashSet<String>>();
+            let lb_provider: Arc<dyn LoadBalancerProvider> = Arc::new(ElbProvider::from_config(&environ));
+            let lbs: Vec<_> = load_balancers::read_regions(lb_provider, &scope)
+                .into_iter()
+                .filter(|lb| lb_names.contains(&canonical_dns_name(&lb.dns_name)))
+                .collect();
+            target_ids = healthy_target_ids(&lbs);
+        }
+        let mut found = instances_with_ips(&addresses, all_instances.clone());
+        found.extend(target_instances(&target_ids, all_instances));
+        found
+    } else {
+        read_matching(provider, &scope, &filters, &pattern, &tags)
+    };
+    // Instances on their way out of an ASG are still running, but shouldn't be picked
+    let alive_matches = in_service_instances(running_instances(matches));
     let mut rng = thread_rng();
-    let sampled_instance = sample(&mut rng, alive_matches.clone(), 1);
-    if sampled_instance.len() == 0 {
+    let sampled_instance = rng.choose(&alive_matches).cloned().into_iter().collect::<Vec<_>>();
+    if sampled_instance.is_empty() {
         println!("The list of matches is {:?}", alive_matches);
         println!("And the sample returned is 0 length");
         println!("No instances matched your request, not doing anything");
@@ -117,11 +374,12 @@ fn main() {
         if debug {
             println!("{:?}", sampled_instance[0]);
         } else {
-            println!("Name: {} IP: {} SSH options: {:?}",
-                    sampled_instance[0].tags.get("Name").unwrap(),
+            println!("Name: {} IP: {} Region: {} SSH options: {:?}",
+                    sampled_instance[0].tags.get("Name").map_or("-", |name| name.as_str()),
                     sampled_instance[0].private_ip_addresses[0],
+                    sampled_instance[0].region,
                      more_ssh_options);
-        launch_ssh(ssh_path.to_string(), more_ssh_options, sampled_instance[0].clone());
+        launch_ssh(ssh_path.to_string(), more_ssh_options, sampled_instance[0].private_ip_addresses[0].clone(), Vec::new());
         }
     }
 }
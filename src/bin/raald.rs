@@ -0,0 +1,68 @@
+extern crate docopt;
+extern crate raal;
+extern crate shellexpand;
+
+use std::collections::HashMap;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use docopt::Docopt;
+
+use raal::cache::CachePolicy;
+use raal::config::{parse_duration, read_config};
+use raal::daemon::{DaemonData, refresh_environment, serve};
+
+const USAGE: &'static str = "
+Keep every configured environment's instances in memory, and answer aal and
+ashuf over a unix socket in the data directory.
+
+Usage:
+  raald [-d <data_dir>] [-i <interval>]
+  raald (-h | --help)
+
+Options:
+  -h --help                 Show this help screen
+  -d <data_dir>             Data directory with cached data and config [default: ~/.raal]
+  -i --interval=<interval>  Time between refreshes of each environment, e.g. 300 or 5m [default: 5m]
+";
+
+fn main() {
+    let version = "0.1.0".to_owned();
+    let parsed_cmdline = Docopt::new(USAGE)
+        .and_then(|d| d.version(Some(version)).parse())
+        .unwrap_or_else(|e| e.exit());
+    let data_dir = shellexpand::full(parsed_cmdline.get_str("-d"))
+        .unwrap()
+        .to_string();
+    let interval = match parse_duration(parsed_cmdline.get_str("-i")) {
+        Ok(seconds) if seconds > 0 => seconds,
+        Ok(_) => {
+            println!("The interval has to be more than nothing");
+            process::exit(1);
+        },
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+    let config = read_config(&data_dir);
+
+    let data: DaemonData = Arc::new(RwLock::new(HashMap::new()));
+    for (env_name, environ) in config.environments.iter() {
+        let data = data.clone();
+        let env_name = env_name.clone();
+        let environ = environ.clone();
+        let data_dir = data_dir.clone();
+        let settings = config.cache_settings(&environ, None, None).unwrap();
+        if settings.policy == CachePolicy::Uncached {
+            println!("Not serving {}, its endpoint isn't AWS", env_name);
+            continue;
+        }
+        thread::spawn(move || refresh_environment(data, env_name, environ, data_dir, settings, interval));
+    }
+
+    if let Err(err) = serve(data, &data_dir) {
+        println!("{}", err);
+        process::exit(1);
+    }
+}
@@ -1,11 +1,30 @@
+extern crate bincode;
+extern crate flate2;
+extern crate fs2;
+extern crate futures;
+extern crate rusoto_autoscaling;
 extern crate rusoto_core;
+extern crate rusoto_credential;
 extern crate rusoto_ec2;
+extern crate rusoto_elasticache;
+extern crate rusoto_elb;
+extern crate rusoto_elbv2;
+extern crate rusoto_ecs;
+extern crate rusoto_rds;
+extern crate rusoto_route53;
+extern crate rusoto_sts;
+extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate chrono;
+extern crate rand;
 extern crate regex;
 extern crate toml;
+#[cfg(test)]
+extern crate http;
+#[cfg(test)]
+extern crate bytes;
 
 pub mod config {
 
@@ -13,17 +32,240 @@ pub mod config {
     use std::io::Read;
     use toml;
     use std::path::Path;
+    use serde::{Deserialize, Deserializer};
+    use serde::de;
+    use cache::{CachePolicy, CacheSettings, DEFAULT_CACHE_TTL, DEFAULT_HISTORY_SNAPSHOTS};
+    use cache_files::CacheEncoding;
     use std::collections::HashMap;
     
     #[derive(Debug, Deserialize)]
     pub struct Config {
         pub environments: HashMap<String, EnvironConfig>, 
+        // Command templates for talking to a resource, keyed by e.g. the
+        // database or cache engine.  {host} and {port} are filled in when launching.
+        #[serde(default)]
+        pub clients: HashMap<String, String>,
+        // How long caches last in every environment that doesn't say
+        // otherwise.  Seconds, or a duration like "10m" (see parse_duration).
+        #[serde(default, deserialize_with = "duration_setting")]
+        pub cache_ttl: Option<i64>,
+        // How cache files are written: "json" (the default), "json.gz" or
+        // "bincode".  Any of them can be read.
+        pub cache_encoding: Option<String>,
+        // How many previous instance caches to keep for each account and
+        // region, for `acache diff`.  0 keeps none.
+        pub history_snapshots: Option<usize>,
     }
-    #[derive(Debug, Deserialize)]
+
+    impl Config {
+        // The configured command for this engine, or a sensible default
+        // for the engines we know about.
+        pub fn client_command(&self, engine: &String) -> Option<String> {
+            if let Some(command) = self.clients.get(engine) {
+                return Some(command.clone());
+            }
+            if engine.contains("postgres") {
+                Some("psql -h {host} -p {port}".to_string())
+            } else if engine.contains("mysql") || engine.contains("mariadb") || engine.starts_with("aurora") {
+                Some("mysql -h {host} -P {port}".to_string())
+            } else if engine == "redis" {
+                Some("redis-cli -h {host} -p {port}".to_string())
+            } else if engine == "memcached" {
+                Some("memcached-tool {host}:{port}".to_string())
+            } else {
+                None
+            }
+        }
+
+        // This environment's cache_ttl, or the global one, or an hour
+        pub fn cache_ttl(&self, environ: &EnvironConfig) -> i64 {
+            environ.cache_ttl
+                .or(self.cache_ttl)
+                .unwrap_or(DEFAULT_CACHE_TTL)
+        }
+
+        // What serde can't catch: every environment needs somewhere to look
+        pub fn check(&self) -> Result<(), String> {
+            for (name, environ) in self.environments.iter() {
+                if environ.all_regions().is_empty() {
+                    return Err(format!("the environment {} has neither region nor regions", name));
+                }
+            }
+            Ok(())
+        }
+
+        pub fn environment(&self, env_name: &str) -> Result<&EnvironConfig, String> {
+            self.environments.get(env_name)
+                .ok_or_else(|| format!("There's no environment named {} in the config", env_name))
+        }
+
+        // How lookups in this environment use the cache.  A ttl or policy
+        // given on the command line comes first, then the environment's, and
+        // then (for the ttl) the global one.  An environment with its own
+        // endpoint isn't really the account, so it never uses the cache.
+        pub fn cache_settings(&self, environ: &EnvironConfig, ttl: Option<&str>, policy: Option<&str>) -> Result<CacheSettings, String> {
+            let ttl = match ttl {
+                Some(ttl) => parse_duration(ttl)?,
+                None => self.cache_ttl(environ),
+            };
+            let policy = match policy.or(environ.cache_policy.as_deref()) {
+                _ if environ.endpoint_url.is_some() => CachePolicy::Uncached,
+                Some(name) => CachePolicy::from_name(name).unwrap_or_else(|err| {
+                    eprintln!("{}, using ttl", err);
+                    CachePolicy::Ttl
+                }),
+                None => CachePolicy::Ttl,
+            };
+            let encoding = match self.cache_encoding {
+                Some(ref name) => CacheEncoding::from_name(name).unwrap_or_else(|err| {
+                    eprintln!("{}, using json", err);
+                    CacheEncoding::Json
+                }),
+                None => CacheEncoding::Json,
+            };
+            Ok(CacheSettings {
+                ttl: ttl,
+                max_stale: environ.max_stale,
+                policy: policy,
+                encoding: encoding,
+                history_snapshots: self.history_snapshots.unwrap_or(DEFAULT_HISTORY_SNAPSHOTS),
+            })
+        }
+    }
+
+    // A number of seconds, or a duration string.  Both of these are allowed
+    // in the config:
+    //   cache_ttl = 600
+    //   cache_ttl = "10m"
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum DurationSetting {
+        Seconds(i64),
+        Text(String),
+    }
+
+    fn duration_setting<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+        where D: Deserializer<'de>
+    {
+        match Option::<DurationSetting>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(DurationSetting::Seconds(seconds)) => check_duration(&seconds.to_string(), seconds).map(Some).map_err(de::Error::custom),
+            Some(DurationSetting::Text(text)) => parse_duration(&text).map(Some).map_err(de::Error::custom),
+        }
+    }
+
+    // chrono's Duration is milliseconds in an i64, so nothing longer fits
+    const MAX_DURATION: i64 = i64::MAX / 1000;
+
+    fn check_duration(text: &str, seconds: i64) -> Result<i64, String> {
+        if seconds < 0 {
+            Err(format!("The duration {} is negative", text))
+        } else if seconds > MAX_DURATION {
+            Err(format!("The duration {} is too long", text))
+        } else {
+            Ok(seconds)
+        }
+    }
+
+    // Seconds from a duration like "90", "90s", "10m", "2h", "1d" or
+    // "1h30m".  A bare number is seconds.
+    pub fn parse_duration(text: &str) -> Result<i64, String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("An empty duration".to_string());
+        }
+        if let Ok(seconds) = text.parse::<i64>() {
+            return check_duration(text, seconds);
+        }
+        let mut total = 0;
+        let mut digits = String::new();
+        for c in text.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            let unit = match c {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                _ => return Err(format!("Couldn't understand the duration {}, use something like 90s, 10m, 2h or 1d", text)),
+            };
+            if digits.is_empty() {
+                return Err(format!("Couldn't understand the duration {}, each unit needs a number before it", text));
+            }
+            total = digits.parse::<i64>().ok()
+                .and_then(|count| count.checked_mul(unit))
+                .and_then(|seconds| seconds.checked_add(total))
+                .ok_or_else(|| format!("The duration {} is too long", text))?;
+            digits.clear();
+        }
+        if !digits.is_empty() {
+            return Err(format!("Couldn't understand the duration {}, {} has no unit", text, digits));
+        }
+        check_duration(text, total)
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
     pub struct EnvironConfig {
         pub account_id: String,
-        pub region: String,
+        // Either a single region, or a list of regions that are all read
+        // and merged together.  Both may be given.
+        pub region: Option<String>,
+        #[serde(default)]
+        pub regions: Vec<String>,
         pub ssh_options: Vec<String>,
+        // Page size for describe-instances.  AWS accepts 5 to 1000, and
+        // picks its own page size when this isn't set.
+        pub max_results: Option<i64>,
+        // Also ask autoscaling for each instance's group and lifecycle
+        // state.  The group is in a tag anyway, but the lifecycle state
+        // (Standby, Terminating:Wait, ...) is only known to autoscaling.
+        #[serde(default)]
+        pub asg_lifecycle: bool,
+        // How long to keep retrying a throttled or failing
+        // describe-instances before giving up (default 60s)
+        #[serde(default, deserialize_with = "duration_setting")]
+        pub api_deadline: Option<i64>,
+        // How long this environment's caches last, overriding the global
+        // cache_ttl.  A busy autoscaled environment wants this short.
+        #[serde(default, deserialize_with = "duration_setting")]
+        pub cache_ttl: Option<i64>,
+        // When a refresh fails, expired cache data is used instead of
+        // nothing.  This caps how old that data may be.
+        #[serde(default, deserialize_with = "duration_setting")]
+        pub max_stale: Option<i64>,
+        // "ttl" (the default) refreshes whenever the cache has expired.
+        // "cache_first" uses expired data as long as the pattern matches
+        // something in it, and only refreshes when it doesn't.
+        pub cache_policy: Option<String>,
+        // A named profile from ~/.aws/credentials (or one with a
+        // credential_process in ~/.aws/config) instead of the default chain
+        pub profile: Option<String>,
+        // Send API calls here instead of the real AWS endpoint, e.g. to
+        // a local mock of EC2 in CI
+        pub endpoint_url: Option<String>,
+        // A role to assume in this environment's account, for when the
+        // default credentials belong to some other account.
+        pub role_arn: Option<String>,
+        pub external_id: Option<String>,
+        pub session_name: Option<String>,
+    }
+
+    impl EnvironConfig {
+        // Every region configured for this environment, without duplicates
+        pub fn all_regions(&self) -> Vec<String> {
+            let mut all = Vec::new();
+            if let Some(ref region) = self.region {
+                all.push(region.clone());
+            }
+            for region in &self.regions {
+                if !all.contains(region) {
+                    all.push(region.clone());
+                }
+            }
+            all
+        }
     }
         
 
@@ -34,7 +276,7 @@ account_id = "123"
 region = "us-east-1"
 ssh_options = ["-l", "ubuntu", "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null"]
 "#;
-        match toml::from_str(&default_config) {
+        match toml::from_str(default_config) {
             Ok(config) => config,
             Err(err) => {
                 println!("The default config couldn't be deserialzed by toml because {:?}. This is a problem in the source: \n{}",
@@ -51,330 +293,3787 @@ ssh_options = ["-l", "ubuntu", "-o", "StrictHostKeyChecking=no", "-o", "UserKnow
         let mut config_file = match File::open(Path::new(&pathname)) {
             Ok(file) =>  file,
             Err(err) => {
-                println!("Couldn't read config file {} because {:?}", pathname, err);
+                eprintln!("Couldn't read config file {} because {:?}", pathname, err);
                 panic!();
             }
         };
         
         config_file.read_to_string(&mut file_bytes).expect("Something went wrong");
-        match toml::from_str(&file_bytes) {
+        let config: Config = match toml::from_str(&file_bytes) {
             Ok(data) => data,
             Err(err) => {
-                println!("Couldn't read toml from {} because {:?}", pathname, err);
-                println!("Using the default config");
+                eprintln!("Couldn't read toml from {} because {:?}", pathname, err);
+                eprintln!("Using the default config");
                 default_config()
             }
+        };
+        if let Err(err) = config.check() {
+            eprintln!("Couldn't use the config in {} because {}", pathname, err);
+            panic!();
         }
+        config
     }
-}
 
-// The general idea for saving and restoring paths will be that first the cache will be consulted
-// when looking for a resource.  If the resource is found, bingo.
-//
-// In the case(s) where the resource can't be found, try the API, and if the API call is successful,
-// record the updated data.  If it is not so successful, then avoid clobbering the current data.
-pub mod ec2_instances {
-    use rusoto_core::{Region};
-    use rusoto_ec2::{Ec2, Ec2Client, DescribeInstancesRequest, Instance, Reservation};
-    // use std::collections::HashMap;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-    use std::fs::{File, rename};
-    use std::path::Path;
-    use std::io::prelude::*;
-    use std::io;
-    use std::io::Read;    
-    use std::collections::{HashMap, HashSet};
-    use std::str::FromStr;
-    use regex::Regex;
+        #[test]
+        fn durations_in_seconds_and_units() {
+            assert_eq!(parse_duration("90"), Ok(90));
+            assert_eq!(parse_duration("90s"), Ok(90));
+            assert_eq!(parse_duration("10m"), Ok(600));
+            assert_eq!(parse_duration("2h"), Ok(7200));
+            assert_eq!(parse_duration("1d"), Ok(86400));
+            assert_eq!(parse_duration("1h30m"), Ok(5400));
+            assert_eq!(parse_duration(" 5m "), Ok(300));
+        }
 
+        #[test]
+        fn durations_that_make_no_sense() {
+            assert!(parse_duration("").is_err());
+            assert!(parse_duration("10x").is_err());
+            assert!(parse_duration("m").is_err());
+            assert!(parse_duration("1h30").is_err());
+            assert!(parse_duration("-5").is_err());
+            assert!(parse_duration("99999999999999999999").is_err());
+            assert!(parse_duration("9999999999999999d").is_err());
+            assert!(parse_duration("106751991167300d").is_err());
+        }
 
+        #[test]
+        fn every_duration_in_the_config_takes_units() {
+            let config: Config = toml::from_str(r#"
+[environments.default]
+account_id = "123"
+region = "us-east-1"
+ssh_options = []
+max_stale = "1d"
+api_deadline = "2m"
+"#).unwrap();
+            let environ = config.environment("default").unwrap();
+            assert_eq!(environ.max_stale, Some(86400));
+            assert_eq!(environ.api_deadline, Some(120));
+            assert!(toml::from_str::<Config>("cache_ttl = -60\n[environments]\n").is_err());
+        }
 
-    use chrono::prelude::*;
-    use chrono::Duration;
+        #[test]
+        fn environments_need_a_region() {
+            assert!(default_config().check().is_ok());
+            let config: Config = toml::from_str(r#"
+[environments.nowhere]
+account_id = "123"
+regions = []
+ssh_options = []
+"#).unwrap();
+            assert_eq!(config.check(), Err("the environment nowhere has neither region nor regions".to_string()));
+        }
 
-    use serde_json;
+        #[test]
+        fn cache_settings_carry_how_the_cache_is_written() {
+            let config: Config = toml::from_str(r#"
+cache_ttl = "10m"
+cache_encoding = "json.gz"
+history_snapshots = 2
 
-    // In the future, this will be a config and a runtime option
-    // Also in the future, bless a tuple of environment variable that will
-    // distinguish account data (Account, API, region) seems reasonable.
-    // "global" seems like an appropriate choice for global APIs like IAM, route53.
+[environments.default]
+account_id = "123"
+region = "us-east-1"
+ssh_options = []
+"#).unwrap();
+            let environ = config.environment("default").unwrap();
+            let settings = config.cache_settings(environ, None, None).unwrap();
+            assert_eq!(settings.ttl, 600);
+            assert_eq!(settings.encoding, CacheEncoding::JsonGz);
+            assert_eq!(settings.history_snapshots, 2);
 
-    // A flat structure to make searching for an instance faster, with a
-    // link back to the instance.
+            let defaults = default_config().cache_settings(environ, Some("90"), None).unwrap();
+            assert_eq!(defaults.ttl, 90);
+            assert_eq!(defaults.encoding, CacheEncoding::Json);
+            assert_eq!(defaults.history_snapshots, DEFAULT_HISTORY_SNAPSHOTS);
+        }
 
-    // TODO: implement a sort on name tags 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
-    pub struct AshufInfo {
-        pub instance_id: String,
-        pub private_ip_addresses: Vec<String>,
-        pub public_ip_addresses: Vec<String>,
-        pub state_name: String,
-        pub launch_time: String,
-        pub availability_zone: String,
-        pub image_ami: String,
-        pub tags: HashMap<String, String>,
+        #[test]
+        fn environments_with_their_own_endpoint_skip_the_cache() {
+            let mut environ = default_config().environment("default").unwrap().clone();
+            environ.cache_policy = Some("cache_first".to_string());
+            environ.endpoint_url = Some("http://localhost:4566".to_string());
+            let settings = default_config().cache_settings(&environ, None, Some("ttl")).unwrap();
+            assert_eq!(settings.policy, CachePolicy::Uncached);
+        }
     }
+}
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
-    pub struct CacheData { // How we'll cache our data
-        written_time: DateTime<Utc>,
-        instance_data: Vec<AshufInfo>,
+pub mod credentials {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use futures::Future;
+    use rusoto_core::{HttpClient, Region};
+    use rusoto_credential::{AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProfileProvider, ProvideAwsCredentials};
+    use rusoto_sts::{Sts, StsClient, GetCallerIdentityRequest, StsAssumeRoleSessionCredentialsProvider};
+
+    use config::EnvironConfig;
+
+    // The credentials for an environment: the default chain or a named
+    // profile, and optionally a role assumed using either of those.  Clones
+    // share the same provider, so the role is only assumed once for every
+    // client that uses it.
+    #[derive(Clone)]
+    pub struct EnvironCredentials {
+        source: Arc<CredentialSource>,
     }
 
+    enum CredentialSource {
+        // The default chain is much bigger than the others, hence the box
+        Default(Box<DefaultCredentialsProvider>),
+        Profile(ProfileProvider),
+        AssumeRole(AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>),
+    }
 
-    pub fn read_without_cache(cache_dir: &String, region_name: &String, aws_account_id: &String) -> Vec<AshufInfo> {
-        let reg = Region::from_str(region_name).unwrap();
-        let client = Ec2Client::new(reg);
-        
-        let mut ec2_request_input = DescribeInstancesRequest::default();
-        ec2_request_input.instance_ids = None;
-        match client.describe_instances(ec2_request_input).sync() {
-            Ok(response) => {
-                let instances = ec2_res_to_instances(response.reservations.unwrap());
-                let instances_data = ashuf_info_list(instances);
-                match write_saved_json(&aws_account_id, &cache_dir, &region_name, &instances_data) {
-                    Ok(msg) => println!("{}", msg),
-                    Err(what_happened) => println!("{}", what_happened),
-                };
-                instances_data
-            },
-            Err(error) => {
-                println!("Error when invoking describe_instances: {:?}", error);
-                Vec::new()
-            }
+    impl EnvironCredentials {
+        pub fn from_config(environ: &EnvironConfig, region: &Region) -> Result<EnvironCredentials, String> {
+            let base = EnvironCredentials::base(environ)?;
+            let source = match environ.role_arn {
+                None => return Ok(base),
+                Some(ref role_arn) => {
+                    let sts = StsClient::new_with(http_client()?, base, region.clone());
+                    let session_name = environ.session_name.clone().unwrap_or("raal".to_string());
+                    let assumed = StsAssumeRoleSessionCredentialsProvider::new(
+                        sts,
+                        role_arn.clone(),
+                        session_name,
+                        environ.external_id.clone(),
+                        None, None, None);
+                    match AutoRefreshingProvider::new(assumed) {
+                        Ok(provider) => CredentialSource::AssumeRole(provider),
+                        Err(err) => return Err(format!("Couldn't assume role {} because {:?}", role_arn, err))
+                    }
+                }
+            };
+            Ok(EnvironCredentials { source: Arc::new(source) })
         }
-    }
-        
-    
-    pub fn read_via_cache(cache_dir: &String, region_name: &String, aws_account_id: &String, cache_ttl: i64) -> Vec<AshufInfo> {
-        
-        // let mut limited_info = Vec::new();
-        // println!("cache_dir: {}", cache_dir);
-        // println!("account: {}", aws_account_id);
-        // println!("region_name: {}", region_name);
-        
-        let limited_info = match ec2_cached_data(&cache_dir, &aws_account_id, &region_name, cache_ttl) {
-            Ok(instances) => {
-                // println!("I'm using cache data");
-                instances
-            },
-            Err(_) => read_without_cache(cache_dir, region_name, aws_account_id)
-        };
 
-        // XXx when ready, map over the regions provided and cache those
-        // so they can be combined afterwards.  But for now, let's do one
-        // region.
-        limited_info
+        // The credentials we start with, before any role is assumed
+        fn base(environ: &EnvironConfig) -> Result<EnvironCredentials, String> {
+            let source = match environ.profile {
+                None => match DefaultCredentialsProvider::new() {
+                    Ok(provider) => CredentialSource::Default(Box::new(provider)),
+                    Err(err) => return Err(format!("Couldn't set up AWS credentials because {:?}", err))
+                },
+                Some(ref profile) => match ProfileProvider::new() {
+                    Ok(mut provider) => {
+                        provider.set_profile(profile.clone());
+                        CredentialSource::Profile(provider)
+                    },
+                    Err(err) => return Err(format!("Couldn't use AWS profile {} because {:?}", profile, err))
+                }
+            };
+            Ok(EnvironCredentials { source: Arc::new(source) })
+        }
     }
 
+    impl ProvideAwsCredentials for EnvironCredentials {
+        type Future = Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
 
-    pub fn ip_addresses_of(instance: &Instance) -> (Vec<String>, Vec<String>) {
-        // A host can have either an ENI in vpc, or a private IP address from an EIP (classic)
-        // This function extracts those addresses, and returns two vectors.  The left
-        // vector contains the private addresses of an instance, and the right vector contains the
-        // public addresses of an instance.
-        let mut private = HashSet::new();
-        let mut public = HashSet::new();
+        fn credentials(&self) -> Self::Future {
+            match *self.source {
+                CredentialSource::Default(ref provider) => Box::new(provider.credentials()),
+                CredentialSource::Profile(ref provider) => Box::new(provider.credentials()),
+                CredentialSource::AssumeRole(ref provider) => Box::new(provider.credentials()),
+            }
+        }
+    }
 
-        if let Some(ref network_interfaces) = instance.network_interfaces {
-            for interface in network_interfaces {
-                if let Some(ref addr) = interface.private_ip_address {
-                    private.insert(addr.clone());
-                }
+    // The region to hand to a client.  With an endpoint_url configured this
+    // is a custom region that keeps the name (for request signing) but sends
+    // everything to that endpoint.
+    pub fn client_region(environ: &EnvironConfig, region_name: &String) -> Result<Region, String> {
+        match environ.endpoint_url {
+            Some(ref endpoint) => Ok(Region::Custom {
+                name: region_name.clone(),
+                endpoint: endpoint.clone(),
+            }),
+            None => match Region::from_str(region_name) {
+                Ok(reg) => Ok(reg),
+                Err(err) => Err(format!("Couldn't use region {} because {:?}", region_name, err))
             }
         }
+    }
 
-        instance.private_ip_address.as_ref().map(|addr| private.insert(addr.clone()));
-        instance.public_ip_address.as_ref().map(|addr| public.insert(addr.clone()));
+    // The region and credentials for calls to one region of an environment's
+    // account.  Assumed roles are checked against the account first.
+    pub fn account_session(environ: &EnvironConfig, region_name: &String, account: &String) -> Result<(Region, EnvironCredentials), String> {
+        let reg = client_region(environ, region_name)?;
+        let credentials = EnvironCredentials::from_config(environ, &reg)?;
+        if environ.role_arn.is_some() {
+            verify_account(&credentials, &reg, account)?;
+        }
+        Ok((reg, credentials))
+    }
 
-        (vec!(private.into_iter().collect()), vec!(public.into_iter().collect()))
+    pub fn http_client() -> Result<HttpClient, String> {
+        match HttpClient::new() {
+            Ok(client) => Ok(client),
+            Err(err) => Err(format!("Couldn't create an http client because {:?}", err))
+        }
     }
 
-    pub fn tags_of(instance: &Instance) -> HashMap<String, String> {
-        // Tags are stored as inconvenient pairs of {"Name": "name", "Value": "Value"}
-        // turn them into simpler key/value map here
-        let mut tags = HashMap::new();
-        if let Some(ref instance_tags) = instance.tags {
-            for tag in instance_tags {
-                if let (&Some(ref key), &Some(ref val)) = (&tag.key, &tag.value) {
-                    tags.insert(key.clone(), val.clone());
-                }
+    // Make sure the credentials really are for the account we think they are.
+    // A typo in a role_arn would otherwise quietly fill one account's cache
+    // with another account's instances.
+    pub fn verify_account(credentials: &EnvironCredentials, region: &Region, account: &String) -> Result<(), String> {
+        let client = StsClient::new_with(http_client()?, credentials.clone(), region.clone());
+        match client.get_caller_identity(GetCallerIdentityRequest::default()).sync() {
+            Ok(identity) => match identity.account {
+                Some(ref caller_account) if caller_account == account => Ok(()),
+                Some(caller_account) => Err(format!("Credentials are for account {}, but the environment is configured for account {}",
+                                                    caller_account, account)),
+                None => Err("get-caller-identity didn't say which account the credentials are for".to_string())
+            },
+            Err(err) => Err(format!("Error when invoking get_caller_identity: {:?}", err))
+        }
+    }
+}
 
-            }
+// AWS throttles calls like describe-instances across a whole account, so a
+// busy account will see RequestLimitExceeded now and then.  Those, and other
+// transient failures, are retried with jittered exponential backoff.
+pub mod retry {
+    use std::fmt::Debug;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use rand::{thread_rng, Rng};
+    use rusoto_core::RusotoError;
+
+    const BASE_DELAY_MS: u64 = 200;
+    const MAX_DELAY_MS: u64 = 20000;
+    // Error codes that mean "slow down" or "try again", which rusoto doesn't
+    // model for ec2 and so hands back in the body of an unknown error
+    const RETRYABLE_CODES: [&'static str; 5] = ["RequestLimitExceeded", "Throttling", "ThrottlingException", "ServiceUnavailable", "InternalError"];
+
+    pub fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+        match *error {
+            RusotoError::HttpDispatch(_) => true,
+            RusotoError::Unknown(ref response) => {
+                let body = String::from_utf8_lossy(&response.body);
+                response.status.is_server_error() || RETRYABLE_CODES.iter().any(|code| body.contains(code))
+            },
+            _ => false,
         }
-        tags
     }
-    
 
-    pub fn ashuf_info_list(instances: Vec<Instance>) -> Vec<AshufInfo> {
-        // Take just the data we want for the AshufInfo struct from the
-        // rusoto::ec2::Instance type, and return a vector of `AshufInfo`
-        //
-        // All data is copied from the instances provided, they are consumed
-        // here.
-        let mut limited_instances: Vec<AshufInfo> = Vec::new();
-        for inst in instances {
-            // println!("This instance is {:?}",  inst);
-            let (private_addrs, public_addrs) = ip_addresses_of(&inst);
-            let tags = tags_of(&inst);
-            // println!("{:?}", addrs);
-            let new_asi = AshufInfo {
-                instance_id: String::from(inst.instance_id.unwrap()),
-                private_ip_addresses: private_addrs,
-                public_ip_addresses: public_addrs,
-                state_name: String::from(inst.state.unwrap().name.unwrap()),
-                launch_time: String::from(inst.launch_time.unwrap()),
-                availability_zone: String::from(inst.placement.unwrap().availability_zone.unwrap()),
-                image_ami: String::from(inst.image_id.unwrap()),
-                tags: tags,
+    // Call f until it succeeds, fails in a way that retrying won't fix, or
+    // the next wait would go past the deadline.  Each failed attempt is
+    // reported on stderr.  Rusoto's errors are big, so they're boxed.
+    pub fn with_backoff<T, E, F>(what: &str, deadline: Instant, mut f: F) -> Result<T, Box<RusotoError<E>>>
+        where E: Debug, F: FnMut() -> Result<T, Box<RusotoError<E>>>
+    {
+        let mut rng = thread_rng();
+        let mut attempt: u32 = 0;
+        loop {
+            attempt += 1;
+            let error = match f() {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
             };
-            limited_instances.push(new_asi);
+            if !is_retryable(&*error) {
+                return Err(error);
+            }
+            // Full jitter: anywhere from nothing up to the exponential delay
+            let ceiling = BASE_DELAY_MS.saturating_mul(1 << attempt.min(16)).min(MAX_DELAY_MS);
+            let delay = Duration::from_millis(rng.gen_range(0, ceiling + 1));
+            if Instant::now() + delay > deadline {
+                eprintln!("{} attempt {} failed, and the deadline has passed: {:?}", what, attempt, error);
+                return Err(error);
+            }
+            eprintln!("{} attempt {} failed, retrying in {}ms: {:?}", what, attempt, delay.as_millis(), error);
+            thread::sleep(delay);
         }
-        limited_instances
     }
-    
 
-    // returns OK on the left, and Not OK on the right.
-    // Let's define that so that on the left are matched instances,
-    // and the right  is unmatched instances.
-    pub fn partition_matches(rexpr: &Regex, tag: &String, instances: Vec<AshufInfo>) -> (Vec<AshufInfo>,  Vec<AshufInfo>) {
-        let (matched, unmatched) = instances
-            .into_iter()
-            .partition(|inst| {
-                if let Some(tval) = inst.tags.get(tag) {
-                    if rexpr.is_match(tval) {  // Match on the value (assuming it can't be None?)
-                        // println!("Matched {:?}", tval);
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            });
-        (matched, unmatched)
-    }
-    
+    #[cfg(test)]
+    mod tests {
+        use bytes::Bytes;
+        use http::{HeaderMap, StatusCode};
+        use rusoto_core::HttpDispatchError;
+        use rusoto_core::request::BufferedHttpResponse;
+        use rusoto_ec2::DescribeInstancesError;
 
-    pub fn instances_matching_regex(pattern: String, interesting_tags: Vec<String>, instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
-        let rexpr = Regex::new(&pattern).unwrap();
-        let mut unmatched_instances = Vec::new();
-        let mut matched_instances = Vec::new();
-        unmatched_instances.extend_from_slice(instances.as_slice());
+        use super::*;
 
-        for ref tag in interesting_tags {
-            let (m, u) = partition_matches(&rexpr, tag, unmatched_instances);
-            // Re-bind unmatched instances for the next loop
-            unmatched_instances = u;
-            matched_instances.extend_from_slice(m.as_slice());
+        fn unknown(status: u16, body: &str) -> RusotoError<DescribeInstancesError> {
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: StatusCode::from_u16(status).unwrap(),
+                body: Bytes::from(body),
+                headers: HeaderMap::default(),
+            })
+        }
+
+        #[test]
+        fn throttling_and_outages_are_retried() {
+            assert!(is_retryable(&unknown(503, "")));
+            assert!(is_retryable(&unknown(400, "<Response><Errors><Error><Code>RequestLimitExceeded</Code></Error></Errors></Response>")));
+            assert!(is_retryable(&RusotoError::<DescribeInstancesError>::HttpDispatch(HttpDispatchError::new("connection reset".to_string()))));
+        }
+
+        #[test]
+        fn mistakes_are_not_retried() {
+            assert!(!is_retryable(&unknown(400, "<Response><Errors><Error><Code>InvalidParameterValue</Code></Error></Errors></Response>")));
+            assert!(!is_retryable(&unknown(403, "<Response><Errors><Error><Code>UnauthorizedOperation</Code></Error></Errors></Response>")));
         }
-        matched_instances
     }
+}
 
-    pub fn running_instances(instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
-        instances.into_iter()
-            .filter(|i| i.state_name == "running".to_string())
-            .collect::<Vec<AshufInfo>>()
+// The data directory may be shared by a team (on NFS, or just a common
+// directory on a bastion), so several people's aal and ashuf can be reading
+// and refreshing the same cache files at once.  Files are written under a
+// unique name and renamed into place so readers never see half a file, and
+// an advisory lock next to each cache file lets only one process at a time
+// go to the API to refresh it.
+pub mod cache_files {
+    use std::fs::{File, OpenOptions, Permissions, metadata, remove_file, rename};
+    use std::io;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::process;
+    use std::time::SystemTime;
+    use fs2::{FileExt, lock_contended_error};
+    use flate2::Compression;
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+
+    // How a cache file is stored.  The encoding is in the file name, so
+    // every one of them can be read no matter which one is being written.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum CacheEncoding {
+        Json,
+        // The same json, gzipped.  Big accounts shrink a lot.
+        JsonGz,
+        // Much faster to read than json, but there's no migrating it, so an
+        // older version is simply refreshed.
+        Bincode,
     }
 
-    pub fn ec2_res_to_instances(reservations: Vec<Reservation>) -> Vec<Instance> {
-        // The ec2 `describe-instances` call returns a structure that describe
-        // reservations, and the reservations contain instances.
-        // Since I pretty much never, ever, ever need to know about reservations,
-        // and always care about instances, I am removing the reservations info
-        // and creating a vector of instances.
+    pub const ALL_ENCODINGS: [CacheEncoding; 3] = [CacheEncoding::Json, CacheEncoding::JsonGz, CacheEncoding::Bincode];
 
+    impl CacheEncoding {
+        pub fn from_name(name: &str) -> Result<CacheEncoding, String> {
+            match name {
+                "json" => Ok(CacheEncoding::Json),
+                "json.gz" => Ok(CacheEncoding::JsonGz),
+                "bincode" => Ok(CacheEncoding::Bincode),
+                _ => Err(format!("Unknown cache encoding {}, expected json, json.gz or bincode", name))
+            }
+        }
 
-        let mut instances = Vec::new();
-        for res in reservations {
-            for res_instances in res.instances {
-                for inst in res_instances {
-                    instances.push(inst);
-                }
+        pub fn extension(&self) -> &'static str {
+            match *self {
+                CacheEncoding::Json => "json",
+                CacheEncoding::JsonGz => "json.gz",
+                CacheEncoding::Bincode => "bincode",
             }
         }
-        instances
     }
-    
 
-    // I think I want to change how this works.  I think it'd be better if
-    // the default  case is that if an instance is found in the cache, then
-    // don't refresh, just use it. Only refresh if an instance isn't found
-    // and the cache has aged out. Or if the user requests it.
-    pub fn ec2_cached_data(cache_dir: &String, account: &String, region_name: &String, cache_ttl: i64) -> Result<Vec<AshufInfo>, String> {
-        // Look at the file at the provided path, and if the age of the
-        // file is less than the specified age, get ec2 instance info
-        // from it instead of from the api.
-        // Otherwise go through the API and return that data
-        // println!("cache_dir: {}", cache_dir);
-        // println!("account: {}", account);
-        // println!("region_name: {}", region_name);
-        let data = match read_saved_json(&cache_dir, &account, &region_name) {
-            Ok(saved_data) => saved_data,
-            Err(error) => return Err(format!("{} while opening {}", error, "cache file"))
-        };
-        let difference = Utc::now().signed_duration_since(data.written_time); // Note that the order matters here.
-        println!("Difference in time between cache record and now is {}", difference);
-            
-        if difference < Duration::seconds(cache_ttl) {
-            // println!("Got data, and the time is valid");
-            Ok(data.instance_data)
+    // Write one cache file (base_pathname is the path without the
+    // extension), and remove the ones in other encodings so they can't be
+    // mistaken for current data.  Returns the path that was written.
+    pub fn write_encoded_file(base_pathname: &String, encoding: CacheEncoding, bytes: &[u8]) -> io::Result<String> {
+        let pathname = format!("{}.{}", base_pathname, encoding.extension());
+        if encoding == CacheEncoding::JsonGz {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            write_cache_file(&pathname, &encoder.finish()?)?;
         } else {
-            // println!("Got data, and the time expired");            
-            Err("Expired".to_string())
+            write_cache_file(&pathname, bytes)?;
         }
+        for other in ALL_ENCODINGS.iter().filter(|other| **other != encoding) {
+            let _ = remove_file(format!("{}.{}", base_pathname, other.extension()));
+        }
+        Ok(pathname)
     }
 
-    /// This function is for saving the data from a call to the API. It's for
-    /// this side-effect only
-    // XXX: add support for (API, region)
-    pub fn write_saved_json(account: &String, cache_dir: &String, region_name: &String, data: &Vec<AshufInfo>) -> io::Result<String> {
-        // Interesting: in rust you can concat a &str onto a String.
-        // Deref coercecions may be an interesting topic?
-        let pathname = format!("{}/{}_{}_ec2_instances.json", cache_dir, account, region_name);
-        
-        let tmp_pathname = pathname.to_owned() + ".tmp";
-
-        println!("starting, writing to {}", tmp_pathname);
+    // Whichever encoding of this cache file was written last, with when
+    pub fn newest_encoded_file(base_pathname: &String) -> Option<(SystemTime, CacheEncoding, String)> {
+        ALL_ENCODINGS.iter()
+            .filter_map(|encoding| {
+                let pathname = format!("{}.{}", base_pathname, encoding.extension());
+                metadata(&pathname).and_then(|m| m.modified()).ok().map(|modified| (modified, *encoding, pathname))
+            })
+            .max_by_key(|&(modified, _, _)| modified)
+    }
 
-        let mut cache_file_new = File::create(Path::new(&tmp_pathname))?;
-        let cache_data = CacheData {
-            written_time: Utc::now(),
-            instance_data: data.to_owned(),
-        };
-        let json_bytes = match serde_json::to_string(&cache_data) {
-            Err(_) => "{}".to_string(),
-            Ok(output) => output
+    // Read whichever encoding of this cache file is newest.  Gzipped json
+    // comes back already inflated, as CacheEncoding::Json.
+    pub fn read_encoded_file(base_pathname: &String) -> io::Result<(CacheEncoding, Vec<u8>)> {
+        let (encoding, pathname) = match newest_encoded_file(base_pathname) {
+            Some((_, encoding, pathname)) => (encoding, pathname),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No cache file for {}", base_pathname))),
         };
-        cache_file_new.write_all(json_bytes.as_bytes())?;
-        // cache_file_new.write_all(serde_json::to_string(&data).unwrap().as_bytes());
+        let mut bytes = Vec::new();
+        let mut cache_file = File::open(&pathname)?;
+        if encoding == CacheEncoding::JsonGz {
+            GzDecoder::new(cache_file).read_to_end(&mut bytes)?;
+            Ok((CacheEncoding::Json, bytes))
+        } else {
+            cache_file.read_to_end(&mut bytes)?;
+            Ok((encoding, bytes))
+        }
+    }
 
-        println!("Wrote the tmp cache file");
+    // Readable by the group, so a shared directory can be used by everyone
+    // in it.  Replacing a file only needs write access to the directory.
+    pub const CACHE_FILE_MODE: u32 = 0o640;
+    // Anyone in the group has to be able to open the lock file to lock it
+    pub const LOCK_FILE_MODE: u32 = 0o660;
+    // Directories in the cache dir (the history) are added to and cleaned
+    // up by everyone in the group.  The setgid bit keeps what's created in
+    // them in the group too.
+    pub const CACHE_DIR_MODE: u32 = 0o2770;
 
-        rename(tmp_pathname, pathname)?;
+    // Write the whole file under a name only this process uses, then rename
+    // it over the old one.
+    pub fn write_cache_file(pathname: &String, bytes: &[u8]) -> io::Result<()> {
+        let tmp_pathname = format!("{}.{}.tmp", pathname, process::id());
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(CACHE_FILE_MODE)
+            .open(&tmp_pathname)
+            .and_then(|mut cache_file| {
+                // The mode above is cut down by the umask, and a umask of
+                // 077 would leave the rest of the group out
+                cache_file.set_permissions(Permissions::from_mode(CACHE_FILE_MODE))?;
+                cache_file.write_all(bytes)?;
+                cache_file.sync_all()
+            })
+            .and_then(|_| rename(&tmp_pathname, pathname));
+        if written.is_err() {
+            let _ = remove_file(&tmp_pathname);
+        }
+        written
+    }
 
-        println!("Re-named the local cache file after getting new data");
+    // Held while a cache file is being refreshed.  The lock is released
+    // when this is dropped, or when the process exits.
+    pub struct CacheLock {
+        file: File,
+    }
 
-        Ok("Cache written out".to_string())
+    impl Drop for CacheLock {
+        fn drop(&mut self) {
+            let _ = self.file.unlock();
+        }
     }
 
-    pub fn read_saved_json(cache_dir: &String, account: &String, region_name: &String) -> io::Result<CacheData> {
-        let pathname = format!("{}/{}_{}_ec2_instances.json", cache_dir, account, region_name);
-        let mut file_bytes = String::new();
-        let mut cache_file = match File::open(Path::new(&pathname)) {
-            Ok(file) =>  file,
-            Err(err) => {
-                // This is failing when there is no file, which is bad, since that's how we all start
-                // println!("Couldn't read cache file {} because {:?}", pathname, err);
-                return Err(err);
+    impl CacheLock {
+        pub fn lock_path(cache_dir: &String, account: &String, region_name: &String, api: &str) -> String {
+            format!("{}/{}_{}_{}.lock", cache_dir, account, region_name, api)
+        }
+
+        fn open(pathname: &String) -> io::Result<File> {
+            match OpenOptions::new().read(true).write(true).create(true).mode(LOCK_FILE_MODE).open(pathname) {
+                Ok(file) => {
+                    // As with cache files, don't let the umask decide.  This
+                    // fails for a lock file someone else created, which
+                    // they've already done for us.
+                    let _ = file.set_permissions(Permissions::from_mode(LOCK_FILE_MODE));
+                    Ok(file)
+                },
+                // Someone else created it, and we can't write it; reading
+                // is enough to hold a lock
+                Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => File::open(pathname),
+                Err(err) => Err(err),
             }
-        };
-        // let mut cache_file = File::open(Path::new(&pathname))?;
+        }
 
-        // println!("starting read_saved_json; path to the instances file is known, and file is opened");
-        
-        cache_file.read_to_string(&mut file_bytes).expect("Something went wrong");
-        let instance_data: CacheData = serde_json::from_str(&file_bytes)?;
-        Ok(instance_data)
+        // Wait for whoever holds the lock to finish
+        pub fn acquire(cache_dir: &String, account: &String, region_name: &String, api: &str) -> io::Result<CacheLock> {
+            let file = CacheLock::open(&CacheLock::lock_path(cache_dir, account, region_name, api))?;
+            file.lock_exclusive()?;
+            Ok(CacheLock { file: file })
+        }
+
+        // None when another process is holding the lock right now
+        pub fn try_acquire(cache_dir: &String, account: &String, region_name: &String, api: &str) -> io::Result<Option<CacheLock>> {
+            let file = CacheLock::open(&CacheLock::lock_path(cache_dir, account, region_name, api))?;
+            match file.try_lock_exclusive() {
+                Ok(()) => Ok(Some(CacheLock { file: file })),
+                Err(ref err) if err.raw_os_error() == lock_contended_error().raw_os_error() => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+    }
+}
+
+// Every kind of resource is cached the same way: a list of them in a file
+// per (account, region, API), with the time it was written.  Route53 isn't
+// regional, so its region is "global".  The file is used until it's older
+// than the ttl, refreshed by one process at a time, and when a refresh fails
+// an expired file is better than nothing.
+pub mod cache {
+    use std::error;
+    use std::fmt;
+    use std::fs::{Permissions, copy, create_dir_all, hard_link, read_dir, remove_file, set_permissions};
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+    use std::thread;
+    use bincode;
+    use chrono::prelude::*;
+    use chrono::Duration;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use serde_json;
+    use serde_json::Value;
+
+    use cache_files::{ALL_ENCODINGS, CACHE_DIR_MODE, CACHE_FILE_MODE, CacheEncoding, CacheLock, newest_encoded_file, read_encoded_file, write_encoded_file};
+    use ec2_instances::{Searchable, instances_matching_regex};
+
+    pub const GLOBAL_REGION: &'static str = "global";
+    // Seconds before a cache is refreshed, unless configured otherwise
+    pub const DEFAULT_CACHE_TTL: i64 = 3600;
+    // Previous caches kept for each (account, region, API) that keeps history
+    pub const DEFAULT_HISTORY_SNAPSHOTS: usize = 5;
+
+    // Bump this whenever the envelope changes, and teach migrate_envelope
+    // (or the resource's own migrate, if it's about the resource) how to
+    // bring the previous version up to it.
+    //
+    // 1: no version, and instances had no region, asg_name or lifecycle_state
+    // 2: the version is recorded
+    // 3: every API uses the same envelope, with the list in `data`
+    pub const CACHE_VERSION: u32 = 3;
+
+    // What each API called its list before version 3
+    const LEGACY_DATA_FIELDS: [&'static str; 5] = ["instance_data", "node_data", "lb_data", "task_data", "record_data"];
+
+    // Anything that can be kept in the cache
+    pub trait CachedResource: Serialize + DeserializeOwned + Clone {
+        // The API's part of the file name, e.g. ec2_instances
+        const API: &'static str;
+        // Whether a refresh keeps the cache it replaces (see save_snapshot)
+        const KEEP_HISTORY: bool = false;
+
+        // Bring the resources in an older cache up to date.  By the time
+        // this is called the list is already in `data`.
+        fn migrate(cache_json: Value, _version: u32) -> Value {
+            cache_json
+        }
+    }
+
+    // Resources that are read one region at a time, and remember which
+    pub trait Regional {
+        fn set_region(&mut self, region_name: &String);
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct CacheKey {
+        pub account: String,
+        pub region: String,
+        pub api: &'static str,
+    }
+
+    impl CacheKey {
+        pub fn new(account: &String, region_name: &String, api: &'static str) -> CacheKey {
+            CacheKey {
+                account: account.clone(),
+                region: region_name.clone(),
+                api: api,
+            }
+        }
+
+        pub fn global(account: &String, api: &'static str) -> CacheKey {
+            CacheKey::new(account, &GLOBAL_REGION.to_string(), api)
+        }
+
+        // The cache file, without the encoding's extension
+        pub fn base_pathname(&self, cache_dir: &String) -> String {
+            format!("{}/{}_{}_{}", cache_dir, self.account, self.region, self.api)
+        }
+
+        fn lock(&self, cache_dir: &String) -> io::Result<CacheLock> {
+            CacheLock::acquire(cache_dir, &self.account, &self.region, self.api)
+        }
+
+        fn try_lock(&self, cache_dir: &String) -> io::Result<Option<CacheLock>> {
+            CacheLock::try_acquire(cache_dir, &self.account, &self.region, self.api)
+        }
+    }
+
+    impl fmt::Display for CacheKey {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} {} {}", self.account, self.region, self.api)
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct CacheEnvelope<T> {
+        // Files from before there was a version are version 1
+        #[serde(default = "unversioned")]
+        pub version: u32,
+        pub written_time: DateTime<Utc>,
+        pub data: Vec<T>,
+    }
+
+    fn unversioned() -> u32 {
+        1
+    }
+
+    // Enough of the envelope to know how to read the rest
+    #[derive(Deserialize)]
+    struct EnvelopeVersion {
+        #[serde(default = "unversioned")]
+        version: u32,
+    }
+
+    // How read_via_cache (and read_matching) treat the cache, and how
+    // write_saved writes it
+    #[derive(Clone, Debug)]
+    pub struct CacheSettings {
+        // Seconds before the cache is refreshed
+        pub ttl: i64,
+        // Seconds that expired data may still be used when a refresh fails.
+        // None means there's no limit.
+        pub max_stale: Option<i64>,
+        // Only read_matching knows the pattern, so only it can use the
+        // cache first
+        pub policy: CachePolicy,
+        pub encoding: CacheEncoding,
+        // Previous caches kept by the APIs that keep history
+        pub history_snapshots: usize,
+    }
+
+    impl Default for CacheSettings {
+        fn default() -> CacheSettings {
+            CacheSettings {
+                ttl: DEFAULT_CACHE_TTL,
+                max_stale: None,
+                policy: CachePolicy::Ttl,
+                encoding: CacheEncoding::Json,
+                history_snapshots: DEFAULT_HISTORY_SNAPSHOTS,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum CachePolicy {
+        // Refresh whenever the cache is older than the ttl
+        Ttl,
+        // Use the cache, however old, when the pattern matches something in
+        // it.  Only when nothing matches does the ttl come into play.
+        CacheFirst,
+        // Never read or write the cache, for data that isn't the account's:
+        // a fixture, or an endpoint other than AWS
+        Uncached,
+    }
+
+    impl CachePolicy {
+        pub fn from_name(name: &str) -> Result<CachePolicy, String> {
+            match name {
+                "ttl" => Ok(CachePolicy::Ttl),
+                "cache_first" => Ok(CachePolicy::CacheFirst),
+                _ => Err(format!("Unknown cache policy {}, expected ttl or cache_first", name))
+            }
+        }
+    }
+
+    // A cache written by a newer raal than this one.  It's left alone rather
+    // than quietly replaced, since whatever wrote it is probably still using it.
+    #[derive(Debug)]
+    pub struct NewerCacheVersion {
+        pub pathname: String,
+        pub version: u32,
+    }
+
+    impl fmt::Display for NewerCacheVersion {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} is cache version {}, but this raal only understands up to version {}. Refresh needed: run with -c to replace it, or upgrade raal",
+                   self.pathname, self.version, CACHE_VERSION)
+        }
+    }
+
+    impl error::Error for NewerCacheVersion {}
+
+    pub fn is_newer_version(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<NewerCacheVersion>())
+    }
+
+    // Why the cache couldn't be used
+    #[derive(Debug)]
+    pub enum CacheMiss {
+        // There's no cache yet, or it couldn't be read
+        Unreadable(String),
+        Expired,
+        // Only an explicit refresh should replace it
+        NewerVersion(String),
+    }
+
+    // Bring an older cache file's envelope up to CACHE_VERSION, then let the
+    // resource do the same for its own fields
+    pub fn migrate_envelope<T: CachedResource>(mut cache_json: Value, version: u32) -> Value {
+        if version < 3 {
+            if let Some(envelope) = cache_json.as_object_mut() {
+                for field in LEGACY_DATA_FIELDS.iter() {
+                    if let Some(data) = envelope.remove(*field) {
+                        envelope.insert("data".to_string(), data);
+                    }
+                }
+            }
+        }
+        let mut cache_json = T::migrate(cache_json, version);
+        if let Some(envelope) = cache_json.as_object_mut() {
+            envelope.insert("version".to_string(), Value::from(CACHE_VERSION));
+        }
+        cache_json
+    }
+
+    fn invalid_data<E>(err: E) -> io::Error
+        where E: Into<Box<dyn error::Error + Send + Sync>>
+    {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    // Save a freshly fetched list, in the configured encoding
+    pub fn write_saved<T: CachedResource>(cache_dir: &String, key: &CacheKey, data: &Vec<T>, settings: &CacheSettings) -> io::Result<String> {
+        if T::KEEP_HISTORY {
+            let keep = settings.history_snapshots;
+            if keep > 0 {
+                if let Err(err) = save_snapshot(cache_dir, key, keep) {
+                    eprintln!("WARNING: couldn't keep the previous cache for {}: {}", key, err);
+                }
+            }
+        }
+        write_saved_as(cache_dir, key, data, settings.encoding)
+    }
+
+    // Where the previous caches for this key are kept
+    pub fn snapshot_dir(cache_dir: &String, key: &CacheKey) -> String {
+        format!("{}/history/{}_{}_{}", cache_dir, key.account, key.region, key.api)
+    }
+
+    // Keep the current cache file, which is about to be replaced, under the
+    // time it was written.  Only the newest `keep` of them are kept.
+    pub fn save_snapshot(cache_dir: &String, key: &CacheKey, keep: usize) -> io::Result<()> {
+        let (modified, encoding, pathname) = match newest_encoded_file(&key.base_pathname(cache_dir)) {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let dir = snapshot_dir(cache_dir, key);
+        create_dir_all(&dir)?;
+        // As with the cache files, don't let the umask decide.  This fails
+        // for directories someone else created, which they've already done.
+        for created in [format!("{}/history", cache_dir), dir.clone()].iter() {
+            let _ = set_permissions(created, Permissions::from_mode(CACHE_DIR_MODE));
+        }
+        let written_time: DateTime<Utc> = DateTime::from(modified);
+        let snapshot = format!("{}/{}.{}", dir, written_time.format(SNAPSHOT_TIME_FORMAT), encoding.extension());
+        // The cache file is replaced by a rename, so a link to it keeps
+        // the old contents
+        if hard_link(&pathname, &snapshot).is_err() {
+            copy(&pathname, &snapshot)?;
+            set_permissions(&snapshot, Permissions::from_mode(CACHE_FILE_MODE))?;
+        }
+        for old_snapshot in list_snapshots(cache_dir, key)?.into_iter().skip(keep) {
+            for encoding in ALL_ENCODINGS.iter() {
+                let _ = remove_file(format!("{}.{}", old_snapshot, encoding.extension()));
+            }
+        }
+        Ok(())
+    }
+
+    const SNAPSHOT_TIME_FORMAT: &'static str = "%Y%m%dT%H%M%SZ";
+
+    // The snapshots for this key, newest first, each without its extension
+    // so it can be given to read_saved_from
+    pub fn list_snapshots(cache_dir: &String, key: &CacheKey) -> io::Result<Vec<String>> {
+        let dir = snapshot_dir(cache_dir, key);
+        let mut stamps = Vec::new();
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(stamps),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(dot) = file_name.find('.') {
+                let stamp = file_name[..dot].to_string();
+                if !stamps.contains(&stamp) {
+                    stamps.push(stamp);
+                }
+            }
+        }
+        // The timestamps sort the same way as the times
+        stamps.sort_by(|a, b| b.cmp(a));
+        Ok(stamps.into_iter().map(|stamp| format!("{}/{}", dir, stamp)).collect())
+    }
+
+    pub fn write_saved_as<T: CachedResource>(cache_dir: &String, key: &CacheKey, data: &Vec<T>, encoding: CacheEncoding) -> io::Result<String> {
+        let envelope = CacheEnvelope {
+            version: CACHE_VERSION,
+            written_time: Utc::now(),
+            data: data.to_owned(),
+        };
+        let bytes = match encoding {
+            CacheEncoding::Bincode => bincode::serialize(&envelope).map_err(invalid_data)?,
+            _ => serde_json::to_vec(&envelope)?,
+        };
+        write_encoded_file(&key.base_pathname(cache_dir), encoding, &bytes)
+    }
+
+    pub fn read_saved<T: CachedResource>(cache_dir: &String, key: &CacheKey) -> io::Result<CacheEnvelope<T>> {
+        read_saved_from(&key.base_pathname(cache_dir))
+    }
+
+    // A cache file (or a snapshot of one) by its path, without the extension
+    pub fn read_saved_from<T: CachedResource>(base_pathname: &String) -> io::Result<CacheEnvelope<T>> {
+        let base_pathname = base_pathname.clone();
+        let (encoding, file_bytes) = read_encoded_file(&base_pathname)?;
+        let version = match encoding {
+            // The version is the first thing in the envelope
+            CacheEncoding::Bincode => bincode::deserialize::<u32>(&file_bytes).map_err(invalid_data)?,
+            _ => serde_json::from_slice::<EnvelopeVersion>(&file_bytes)?.version,
+        };
+        if version > CACHE_VERSION {
+            return Err(invalid_data(NewerCacheVersion {
+                pathname: base_pathname,
+                version: version,
+            }));
+        }
+        let envelope = match encoding {
+            // Binary caches can't be migrated, they're just refreshed
+            CacheEncoding::Bincode if version < CACHE_VERSION =>
+                return Err(invalid_data(format!("{} is an old binary cache, it needs a refresh", base_pathname))),
+            CacheEncoding::Bincode => bincode::deserialize(&file_bytes).map_err(invalid_data)?,
+            _ if version < CACHE_VERSION => {
+                let cache_json: Value = serde_json::from_slice(&file_bytes)?;
+                serde_json::from_value(migrate_envelope::<T>(cache_json, version))?
+            },
+            _ => serde_json::from_slice(&file_bytes)?,
+        };
+        Ok(envelope)
+    }
+
+    // The cached list, if it's younger than the ttl
+    pub fn cached_data<T: CachedResource>(cache_dir: &String, key: &CacheKey, cache_ttl: i64) -> Result<Vec<T>, CacheMiss> {
+        let envelope = match read_saved::<T>(cache_dir, key) {
+            Ok(envelope) => envelope,
+            Err(ref error) if is_newer_version(error) => return Err(CacheMiss::NewerVersion(error.to_string())),
+            Err(error) => return Err(CacheMiss::Unreadable(format!("{} while opening {}", error, "cache file")))
+        };
+        let difference = Utc::now().signed_duration_since(envelope.written_time); // Note that the order matters here.
+        if difference < Duration::seconds(cache_ttl) {
+            Ok(envelope.data)
+        } else {
+            Err(CacheMiss::Expired)
+        }
+    }
+
+    // Whatever is in the cache, however old it is, as long as it's within
+    // max_stale.  There's always a warning, since this data may be wrong.
+    pub fn stale_cached_data<T: CachedResource>(cache_dir: &String, key: &CacheKey, max_stale: Option<i64>) -> Option<Vec<T>> {
+        let envelope = read_saved::<T>(cache_dir, key).ok()?;
+        let age = Utc::now().signed_duration_since(envelope.written_time);
+        if let Some(max_stale) = max_stale {
+            if age > Duration::seconds(max_stale) {
+                eprintln!("WARNING: the API call failed, and the cache for {} is {} old, which is past max_stale",
+                          key, describe_age(age));
+                return None;
+            }
+        }
+        eprintln!("WARNING: the API call failed, using the expired cache for {} from {} ago",
+                  key, describe_age(age));
+        Some(envelope.data)
+    }
+
+    // While another process refreshes the cache, the previous data, if
+    // there is any and it's within max_stale
+    fn refreshing_cached_data<T: CachedResource>(cache_dir: &String, key: &CacheKey, max_stale: Option<i64>) -> Option<Vec<T>> {
+        let envelope = match read_saved::<T>(cache_dir, key) {
+            Ok(envelope) => envelope,
+            Err(_) => return None,
+        };
+        let age = Utc::now().signed_duration_since(envelope.written_time);
+        if max_stale.is_some_and(|max_stale| age > Duration::seconds(max_stale)) {
+            return None;
+        }
+        eprintln!("{} is being refreshed by another process, using the cache from {} ago",
+                  key, describe_age(age));
+        Some(envelope.data)
+    }
+
+    // A short, human readable age like "3d 4h" or "12m 5s"
+    pub fn describe_age(age: Duration) -> String {
+        let seconds = age.num_seconds().max(0);
+        let (days, hours, minutes, secs) = (seconds / 86400, (seconds % 86400) / 3600, (seconds % 3600) / 60, seconds % 60);
+        if days > 0 {
+            format!("{}d {}h", days, hours)
+        } else if hours > 0 {
+            format!("{}h {}m", hours, minutes)
+        } else {
+            format!("{}m {}s", minutes, secs)
+        }
+    }
+
+    // Ask the API and save what it returns
+    pub fn fetch_and_save<T, F>(cache_dir: &String, key: &CacheKey, settings: &CacheSettings, fetch: F) -> Result<Vec<T>, String>
+        where T: CachedResource, F: FnOnce() -> Result<Vec<T>, String>
+    {
+        let data = fetch()?;
+        match write_saved(cache_dir, key, &data, settings) {
+            Ok(pathname) => eprintln!("Wrote {}", pathname),
+            Err(what_happened) => eprintln!("{}", what_happened),
+        };
+        Ok(data)
+    }
+
+    // What was read, or nothing after saying why not
+    fn or_nothing<T>(read: Result<Vec<T>, String>) -> Vec<T> {
+        read.unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            Vec::new()
+        })
+    }
+
+    // A refresh that was asked for.  It still waits its turn behind anyone
+    // else writing the same cache.
+    pub fn read_without_cache<T, F>(cache_dir: &String, key: &CacheKey, settings: &CacheSettings, fetch: F) -> Vec<T>
+        where T: CachedResource, F: FnOnce() -> Result<Vec<T>, String>
+    {
+        or_nothing(try_read_without_cache(cache_dir, key, settings, fetch))
+    }
+
+    pub fn try_read_without_cache<T, F>(cache_dir: &String, key: &CacheKey, settings: &CacheSettings, fetch: F) -> Result<Vec<T>, String>
+        where T: CachedResource, F: FnOnce() -> Result<Vec<T>, String>
+    {
+        if settings.policy == CachePolicy::Uncached {
+            return fetch();
+        }
+        let _lock = key.lock(cache_dir)
+            .map_err(|err| eprintln!("WARNING: couldn't lock the cache for {}: {}", key, err))
+            .ok();
+        fetch_and_save(cache_dir, key, settings, fetch)
+    }
+
+    // The cache while it's fresh, otherwise whatever fetch returns
+    pub fn read_via_cache<T, F>(cache_dir: &String, key: &CacheKey, settings: &CacheSettings, fetch: F) -> Vec<T>
+        where T: CachedResource, F: FnOnce() -> Result<Vec<T>, String>
+    {
+        or_nothing(try_read_via_cache(cache_dir, key, settings, fetch))
+    }
+
+    // The same, but an error when there's nothing to go on, rather than
+    // nothing at all
+    pub fn try_read_via_cache<T, F>(cache_dir: &String, key: &CacheKey, settings: &CacheSettings, fetch: F) -> Result<Vec<T>, String>
+        where T: CachedResource, F: FnOnce() -> Result<Vec<T>, String>
+    {
+        if settings.policy == CachePolicy::Uncached {
+            return fetch();
+        }
+        match cached_data(cache_dir, key, settings.ttl) {
+            Ok(data) => return Ok(data),
+            Err(CacheMiss::NewerVersion(msg)) => return Err(msg),
+            Err(_) => (),
+        }
+
+        // Only one process sharing this cache dir refreshes it at a time
+        let _lock = match key.try_lock(cache_dir) {
+            Ok(Some(lock)) => Some(lock),
+            Ok(None) => {
+                // Someone else is already asking amazon.  If there's old
+                // data to go on, use it rather than waiting for them.
+                if let Some(data) = refreshing_cached_data(cache_dir, key, settings.max_stale) {
+                    return Ok(data);
+                }
+                eprintln!("Waiting for another process to refresh {}", key);
+                key.lock(cache_dir)
+                    .map_err(|err| eprintln!("WARNING: couldn't lock the cache for {}: {}", key, err))
+                    .ok()
+            },
+            Err(err) => {
+                eprintln!("WARNING: couldn't lock the cache for {}: {}", key, err);
+                None
+            }
+        };
+        // Whoever held the lock may have just refreshed it
+        if let Ok(data) = cached_data(cache_dir, key, settings.ttl) {
+            return Ok(data);
+        }
+
+        fetch_and_save(cache_dir, key, settings, fetch).or_else(|error| {
+            // Amazon is melting down (or we can't reach it), so old data is
+            // better than none at all
+            eprintln!("{}", error);
+            stale_cached_data(cache_dir, key, settings.max_stale)
+                .ok_or_else(|| format!("Nothing to go on for {}", key))
+        })
+    }
+
+    // Run read for every region at once, each on its own thread, and put
+    // together what they found, each marked with its region.  Returns that,
+    // and why each region that failed did.
+    pub fn read_each_region<T, R>(regions: &Vec<String>, read: R) -> (Vec<T>, Vec<String>)
+        where T: CachedResource + Regional + Send + 'static, R: Fn(&String) -> Result<Vec<T>, String> + Send + Sync + 'static
+    {
+        let read = Arc::new(read);
+        let handles = regions.iter()
+            .map(|region_name| {
+                let read = read.clone();
+                let region_name = region_name.clone();
+                thread::spawn(move || {
+                    let mut found = read(&region_name)?;
+                    for resource in found.iter_mut() {
+                        resource.set_region(&region_name);
+                    }
+                    Ok(found)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut merged = Vec::new();
+        let mut failures = Vec::new();
+        for (handle, region_name) in handles.into_iter().zip(regions) {
+            match handle.join() {
+                Ok(Ok(found)) => merged.extend(found),
+                Ok(Err(error)) => failures.push(error),
+                Err(_) => failures.push(format!("Reading {} failed, its {} are missing", region_name, T::API)),
+            }
+        }
+        (merged, failures)
+    }
+
+    // What every region that could be read found, after saying why the
+    // others couldn't
+    pub fn report_failures<T>(read: (Vec<T>, Vec<String>)) -> Vec<T> {
+        let (found, failures) = read;
+        for failure in failures {
+            eprintln!("{}", failure);
+        }
+        found
+    }
+
+    // Every region's resources, through the cache or (when it's bypassed)
+    // straight from fetch, which is given the region to ask.
+    pub fn read_regions<T, F>(scope: &CacheScope, fetch: F) -> Vec<T>
+        where T: CachedResource + Regional + Send + 'static, F: Fn(&String) -> Result<Vec<T>, String> + Send + Sync + 'static
+    {
+        report_failures(try_read_regions(scope, fetch))
+    }
+
+    // The same, along with why each region that failed did (see read_each_region)
+    pub fn try_read_regions<T, F>(scope: &CacheScope, fetch: F) -> (Vec<T>, Vec<String>)
+        where T: CachedResource + Regional + Send + 'static, F: Fn(&String) -> Result<Vec<T>, String> + Send + Sync + 'static
+    {
+        let scope_for_regions = scope.clone();
+        read_each_region(&scope.regions, move |region_name| {
+            let scope = &scope_for_regions;
+            let key = CacheKey::new(&scope.account, region_name, T::API);
+            if scope.bypass_cache {
+                try_read_without_cache(&scope.cache_dir, &key, &scope.settings, || fetch(region_name))
+            } else {
+                try_read_via_cache(&scope.cache_dir, &key, &scope.settings, || fetch(region_name))
+            }
+        })
+    }
+
+    // Which caches a lookup goes through, and how: one account's caches, for
+    // some of its regions, in a data dir
+    #[derive(Clone, Debug)]
+    pub struct CacheScope {
+        pub cache_dir: String,
+        pub account: String,
+        pub regions: Vec<String>,
+        // Go to the API, however fresh the cache is
+        pub bypass_cache: bool,
+        pub settings: CacheSettings,
+    }
+
+    impl CacheScope {
+        // Whether what's cached (or what raald has) may answer a lookup
+        pub fn uses_cache(&self) -> bool {
+            !self.bypass_cache && self.settings.policy != CachePolicy::Uncached
+        }
+    }
+
+    // Everything in the caches of these regions, no matter how old, for the
+    // cache_first policy.  A region without a cache just contributes nothing.
+    pub fn read_regions_cached<T: CachedResource + Regional>(scope: &CacheScope) -> Vec<T> {
+        let mut merged = Vec::new();
+        for region_name in &scope.regions {
+            let key = CacheKey::new(&scope.account, region_name, T::API);
+            if let Ok(envelope) = read_saved::<T>(&scope.cache_dir, &key) {
+                for mut resource in envelope.data {
+                    resource.set_region(region_name);
+                    merged.push(resource);
+                }
+            }
+        }
+        merged
+    }
+
+    // The resources whose fields match the pattern.  With cache_first, what's
+    // cached is used however old it is, as long as something usable in it
+    // matches; otherwise (and with ttl) read is called for the usual cached read.
+    pub fn read_matching<T, R>(scope: &CacheScope, pattern: &String, fields: &Vec<String>, read: R) -> Vec<T>
+        where T: CachedResource + Regional + Searchable, R: FnOnce() -> Vec<T>
+    {
+        if scope.settings.policy == CachePolicy::CacheFirst && !scope.bypass_cache {
+            let cached = read_regions_cached::<T>(scope);
+            let matches = instances_matching_regex(pattern.clone(), fields.clone(), cached);
+            if matches.iter().any(|found| found.is_usable()) {
+                return matches;
+            }
+        }
+        instances_matching_regex(pattern.clone(), fields.clone(), read())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::env;
+        use std::fs::{File, create_dir_all, metadata, remove_dir_all};
+        use std::io::Write;
+        use std::process;
+        use bincode;
+        use serde_json::Value;
+
+        use super::*;
+        use ec2_instances::AshufInfo;
+        use ec2_instances::tests::instance;
+
+        fn test_dir(name: &str) -> String {
+            let dir = env::temp_dir().join(format!("raal_cache_{}_{}", name, process::id()));
+            let _ = remove_dir_all(&dir);
+            create_dir_all(&dir).unwrap();
+            dir.to_string_lossy().into_owned()
+        }
+
+        fn write_file(pathname: &String, bytes: &[u8]) {
+            File::create(pathname).unwrap().write_all(bytes).unwrap();
+        }
+
+        // A version 1 cache: no version, the list in instance_data, and no asg_name
+        const VERSION_1: &'static str = r#"{
+            "written_time": "2019-06-01T12:00:00Z",
+            "instance_data": [{
+                "instance_id": "i-1",
+                "private_ip_addresses": ["10.0.0.1"],
+                "public_ip_addresses": [],
+                "state_name": "running",
+                "launch_time": "2019-06-01T11:00:00.000Z",
+                "availability_zone": "us-east-1a",
+                "image_ami": "ami-1",
+                "tags": {"Name": "web-1", "aws:autoscaling:groupName": "web-asg"}
+            }]
+        }"#;
+
+        #[test]
+        fn migrate_envelope_renames_the_list_and_sets_the_version() {
+            let cache_json: Value = serde_json::from_str(VERSION_1).unwrap();
+            let migrated = migrate_envelope::<AshufInfo>(cache_json, 1);
+            assert_eq!(migrated["version"], Value::from(CACHE_VERSION));
+            assert!(migrated.get("instance_data").is_none());
+            assert_eq!(migrated["data"][0]["asg_name"], Value::from("web-asg"));
+        }
+
+        #[test]
+        fn old_json_caches_are_migrated_on_read() {
+            let dir = test_dir("old_json");
+            let base_pathname = format!("{}/123_us-east-1_ec2_instances", dir);
+            write_file(&format!("{}.json", base_pathname), VERSION_1.as_bytes());
+            let envelope = read_saved_from::<AshufInfo>(&base_pathname).unwrap();
+            assert_eq!(envelope.version, CACHE_VERSION);
+            assert_eq!(envelope.data.len(), 1);
+            assert_eq!(envelope.data[0].asg_name, Some("web-asg".to_string()));
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn newer_caches_are_left_alone() {
+            let dir = test_dir("newer");
+            let base_pathname = format!("{}/123_us-east-1_ec2_instances", dir);
+            let newer = format!(r#"{{"version": {}, "written_time": "2019-06-01T12:00:00Z", "data": [], "from_the_future": true}}"#,
+                                CACHE_VERSION + 1);
+            write_file(&format!("{}.json", base_pathname), newer.as_bytes());
+            let error = read_saved_from::<AshufInfo>(&base_pathname).unwrap_err();
+            assert!(is_newer_version(&error));
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn old_binary_caches_need_a_refresh() {
+            let dir = test_dir("old_bincode");
+            let base_pathname = format!("{}/123_us-east-1_ec2_instances", dir);
+            let bytes = bincode::serialize(&(CACHE_VERSION - 1)).unwrap();
+            write_file(&format!("{}.bincode", base_pathname), &bytes);
+            let error = read_saved_from::<AshufInfo>(&base_pathname).unwrap_err();
+            assert!(!is_newer_version(&error));
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn every_encoding_reads_back_what_was_written() {
+            let dir = test_dir("encodings");
+            let key = CacheKey::new(&"123".to_string(), &"us-east-1".to_string(), AshufInfo::API);
+            let cache_json: Value = serde_json::from_str(VERSION_1).unwrap();
+            let envelope: CacheEnvelope<AshufInfo> = serde_json::from_value(migrate_envelope::<AshufInfo>(cache_json, 1)).unwrap();
+            for encoding in ALL_ENCODINGS.iter() {
+                write_saved_as(&dir, &key, &envelope.data, *encoding).unwrap();
+                let read_back = read_saved::<AshufInfo>(&dir, &key).unwrap();
+                assert_eq!(read_back.data[0].instance_id, "i-1");
+            }
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn failed_refreshes_fall_back_to_the_cache_within_max_stale() {
+            let dir = test_dir("max_stale");
+            let key = CacheKey::new(&"123".to_string(), &"us-east-1".to_string(), AshufInfo::API);
+            let two_hours_ago = CacheEnvelope {
+                version: CACHE_VERSION,
+                written_time: Utc::now() - Duration::hours(2),
+                data: vec![instance("i-1", "us-east-1a")],
+            };
+            write_file(&format!("{}.json", key.base_pathname(&dir)), serde_json::to_string(&two_hours_ago).unwrap().as_bytes());
+            let failing = || -> Result<Vec<AshufInfo>, String> { Err("The API is down".to_string()) };
+
+            let within = CacheSettings { ttl: 60, max_stale: Some(3 * 3600), ..Default::default() };
+            let stale = read_via_cache(&dir, &key, &within, failing);
+            assert_eq!(stale.len(), 1);
+            assert_eq!(stale[0].instance_id, "i-1");
+
+            let past = CacheSettings { ttl: 60, max_stale: Some(3600), ..Default::default() };
+            assert!(read_via_cache(&dir, &key, &past, failing).is_empty());
+            assert!(try_read_via_cache(&dir, &key, &past, failing).is_err());
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn snapshots_are_shared_with_the_group() {
+            let dir = test_dir("snapshot_modes");
+            let key = CacheKey::new(&"123".to_string(), &"us-east-1".to_string(), AshufInfo::API);
+            write_saved_as(&dir, &key, &vec![instance("i-1", "us-east-1a")], CacheEncoding::Json).unwrap();
+            save_snapshot(&dir, &key, 1).unwrap();
+            let mode = |pathname: &String| metadata(pathname).unwrap().permissions().mode() & 0o7777;
+            assert_eq!(mode(&format!("{}/history", dir)), CACHE_DIR_MODE);
+            assert_eq!(mode(&snapshot_dir(&dir, &key)), CACHE_DIR_MODE);
+            let snapshot = &list_snapshots(&dir, &key).unwrap()[0];
+            assert_eq!(mode(&format!("{}.json", snapshot)), CACHE_FILE_MODE);
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn cache_first_reads_only_when_the_cache_has_no_match() {
+            let dir = test_dir("cache_first");
+            let key = CacheKey::new(&"123".to_string(), &"us-east-1".to_string(), AshufInfo::API);
+            let mut stopped = instance("i-2", "us-east-1a");
+            stopped.state_name = "stopped".to_string();
+            stopped.tags.insert("Name".to_string(), "db-2".to_string());
+            write_saved_as(&dir, &key, &vec![instance("i-1", "us-east-1a"), stopped], CacheEncoding::Json).unwrap();
+            let scope = CacheScope {
+                cache_dir: dir.clone(),
+                account: "123".to_string(),
+                regions: vec!["us-east-1".to_string()],
+                bypass_cache: false,
+                settings: CacheSettings { ttl: 0, policy: CachePolicy::CacheFirst, ..Default::default() },
+            };
+            let fields = vec!["Name".to_string()];
+
+            let cached = read_matching(&scope, &"web".to_string(), &fields, || -> Vec<AshufInfo> {
+                panic!("a match in the cache shouldn't be read again")
+            });
+            assert_eq!(cached.len(), 1);
+            assert_eq!(cached[0].instance_id, "i-1");
+
+            // Only a stopped instance matches, so it's worth asking again
+            let fetched = read_matching(&scope, &"db".to_string(), &fields, || vec![instance("i-3", "us-east-1a")]);
+            assert!(fetched.is_empty());
+            let mut running = instance("i-2", "us-east-1a");
+            running.tags.insert("Name".to_string(), "db-2".to_string());
+            let fetched = read_matching(&scope, &"db".to_string(), &fields, || vec![running.clone()]);
+            assert_eq!(fetched[0].state_name, "running");
+            remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+// The general idea for saving and restoring paths will be that first the cache will be consulted
+// when looking for a resource.  If the resource is found, bingo.
+//
+// In the case(s) where the resource can't be found, try the API, and if the API call is successful,
+// record the updated data.  If it is not so successful, then avoid clobbering the current data.
+pub mod ec2_instances {
+    use rusoto_autoscaling::{Autoscaling, AutoscalingClient, AutoScalingInstancesType, DescribeAutoScalingInstancesType};
+    use rusoto_ec2::{Ec2, Ec2Client, DescribeInstancesRequest, Filter, Instance, Reservation};
+    // use std::collections::HashMap;
+
+    use std::fs::File;
+    use std::path::Path;
+    use std::io::Read;    
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::{Duration as StdDuration, Instant};
+    use regex::Regex;
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use retry::with_backoff;
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+
+    use serde_json;
+    use serde_json::Value;
+
+    // In the future, this will be a config and a runtime option
+    // Also in the future, bless a tuple of environment variable that will
+    // distinguish account data (Account, API, region) seems reasonable.
+    // "global" seems like an appropriate choice for global APIs like IAM, route53.
+
+    // A flat structure to make searching for an instance faster, with a
+    // link back to the instance.
+
+    // TODO: implement a sort on name tags 
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct AshufInfo {
+        pub instance_id: String,
+        pub private_ip_addresses: Vec<String>,
+        pub public_ip_addresses: Vec<String>,
+        pub state_name: String,
+        pub launch_time: String,
+        pub availability_zone: String,
+        pub image_ami: String,
+        pub tags: HashMap<String, String>,
+        // Filled in when the instance is read, since caches written before
+        // there were multiple regions don't have it.
+        #[serde(default)]
+        pub region: String,
+        // The auto scaling group this instance belongs to, if any
+        #[serde(default)]
+        pub asg_name: Option<String>,
+        // Only known when autoscaling was asked (see `asg_lifecycle`)
+        #[serde(default)]
+        pub lifecycle_state: Option<String>,
+    }
+
+    const DEFAULT_API_DEADLINE: u64 = 60;
+
+    // The tag that autoscaling puts on the instances it launches.  Matching
+    // against this name matches the instance's `asg_name`.
+    pub const ASG_TAG: &'static str = "aws:autoscaling:groupName";
+
+    impl CachedResource for AshufInfo {
+        const API: &'static str = "ec2_instances";
+        // For `acache diff`
+        const KEEP_HISTORY: bool = true;
+
+        fn migrate(mut cache_json: Value, version: u32) -> Value {
+            if version < 2 {
+                // asg_name came from the autoscaling tag, which the old files have
+                if let Some(instances) = cache_json.get_mut("data").and_then(|v| v.as_array_mut()) {
+                    for inst in instances.iter_mut() {
+                        let asg_name = inst.get("tags")
+                            .and_then(|tags| tags.get(ASG_TAG))
+                            .cloned()
+                            .unwrap_or(Value::Null);
+                        if let Some(inst) = inst.as_object_mut() {
+                            inst.entry("asg_name").or_insert(asg_name);
+                        }
+                    }
+                }
+            }
+            cache_json
+        }
+    }
+
+    impl Regional for AshufInfo {
+        fn set_region(&mut self, region_name: &String) {
+            self.region = region_name.clone();
+        }
+    }
+
+    // Anything that can produce the instances of an account in a region.
+    // The rusoto ec2 client is the real one, but a fixture file of canned
+    // `AshufInfo` data lets everything downstream of the API (the cache, the
+    // matching, aal and ashuf) run without talking to AWS.
+    //
+    // The filters are only a way to ask the API for less data.  Callers still
+    // match the results themselves, so a provider is free to ignore them.
+    //
+    // Regions are read concurrently, hence Send + Sync.
+    pub trait InstanceProvider: Send + Sync {
+        fn instances(&self, account: &String, region_name: &String, filters: &Vec<InstanceFilter>) -> Result<Vec<AshufInfo>, String>;
+    }
+
+    // A describe-instances filter, e.g. {"tag:Name": ["web-*"]}
+    #[derive(Clone, Debug)]
+    pub struct InstanceFilter {
+        pub name: String,
+        pub values: Vec<String>,
+    }
+
+    // Characters that make a regex more than a literal string
+    const REGEX_META: &'static str = "\\.+*?()|[]{}^$";
+
+    // Turn a pattern into server-side filters, when that can be done without
+    // changing what matches.  A literal pattern like `web` becomes `*web*`, and
+    // an anchored literal like `^web-4` becomes `web-4*`.  Anything else is left
+    // for the regex to handle after the response comes back.
+    //
+    // Since filters are AND-ed together, a pattern that's matched against more
+    // than one tag can only be pushed down as `tag-value`, which matches any tag.
+    // That returns a few extras, but never misses an instance.
+    pub fn pattern_filters(pattern: &String, interesting_tags: &Vec<String>, running_only: bool) -> Vec<InstanceFilter> {
+        let mut filters = Vec::new();
+
+        let (anchored, literal) = match pattern.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, &pattern[..]),
+        };
+        if !literal.is_empty() && !literal.chars().any(|c| REGEX_META.contains(c)) {
+            let filter_name = if interesting_tags.len() == 1 {
+                format!("tag:{}", interesting_tags[0])
+            } else {
+                "tag-value".to_string()
+            };
+            let value = if anchored {
+                format!("{}*", literal)
+            } else {
+                format!("*{}*", literal)
+            };
+            filters.push(InstanceFilter { name: filter_name, values: vec![value] });
+        }
+
+        if running_only {
+            filters.push(InstanceFilter {
+                name: "instance-state-name".to_string(),
+                values: vec!["running".to_string()],
+            });
+        }
+        filters
+    }
+
+    // Calls describe-instances with the environment's credentials,
+    // following next_token until every page has been read.
+    pub struct Ec2Provider {
+        pub environ: EnvironConfig,
+    }
+
+    impl Ec2Provider {
+        pub fn from_config(environ: &EnvironConfig) -> Ec2Provider {
+            Ec2Provider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl InstanceProvider for Ec2Provider {
+        fn instances(&self, account: &String, region_name: &String, filters: &Vec<InstanceFilter>) -> Result<Vec<AshufInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, region_name, account)?;
+            let client = Ec2Client::new_with(http_client()?, credentials.clone(), reg.clone());
+            let mut instances_data = describe_instances(&client, &self.environ, filters)?;
+
+            if self.environ.asg_lifecycle {
+                let asg_client = AutoscalingClient::new_with(http_client()?, credentials, reg);
+                let asg_details = asg_instance_details(&asg_client)?;
+                for inst in instances_data.iter_mut() {
+                    if let Some((group, state)) = asg_details.get(&inst.instance_id) {
+                        inst.asg_name = Some(group.clone());
+                        inst.lifecycle_state = Some(state.clone());
+                    }
+                }
+            }
+            Ok(instances_data)
+        }
+    }
+
+    // Every page of describe-instances for these filters, retried when
+    // throttled.  The deadline covers every page, not each one.
+    pub fn describe_instances(client: &Ec2Client, environ: &EnvironConfig, filters: &Vec<InstanceFilter>) -> Result<Vec<AshufInfo>, String> {
+        Ok(ashuf_info_list(describe_instance_list(client, environ, filters)?))
+    }
+
+    // The same, as rusoto has them, for callers that need more than AshufInfo keeps
+    pub fn describe_instance_list(client: &Ec2Client, environ: &EnvironConfig, filters: &Vec<InstanceFilter>) -> Result<Vec<Instance>, String> {
+        let ec2_filters = if filters.is_empty() {
+            None
+        } else {
+            Some(filters.iter()
+                 .map(|f| Filter { name: Some(f.name.clone()), values: Some(f.values.clone()) })
+                 .collect::<Vec<Filter>>())
+        };
+
+        let deadline = Instant::now() + StdDuration::from_secs(environ.api_deadline.map_or(DEFAULT_API_DEADLINE, |seconds| seconds as u64));
+        let mut instances = Vec::new();
+        let mut next_token = None;
+        loop {
+            let ec2_request_input = DescribeInstancesRequest {
+                filters: ec2_filters.clone(),
+                max_results: environ.max_results,
+                next_token: next_token,
+                ..Default::default()
+            };
+            let page = with_backoff("describe_instances", deadline, || {
+                client.describe_instances(ec2_request_input.clone()).sync().map_err(Box::new)
+            });
+            match page {
+                Ok(response) => {
+                    instances.extend(ec2_res_to_instances(response.reservations.unwrap_or(Vec::new())));
+                    // The last page has no token (or sometimes an empty one)
+                    next_token = response.next_token.filter(|token| !token.is_empty());
+                    if next_token.is_none() {
+                        break;
+                    }
+                },
+                Err(error) => return Err(format!("Error when invoking describe_instances: {:?}", error))
+            }
+        }
+        Ok(instances)
+    }
+
+    // Map each instance id that's in an auto scaling group to its group name
+    // and lifecycle state.
+    fn asg_instance_details(client: &AutoscalingClient) -> Result<HashMap<String, (String, String)>, String> {
+        let mut details = HashMap::new();
+        let mut next_token = None;
+        loop {
+            let request_input = DescribeAutoScalingInstancesType {
+                next_token: next_token,
+                ..Default::default()
+            };
+            let response: AutoScalingInstancesType = match client.describe_auto_scaling_instances(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking describe_auto_scaling_instances: {:?}", error))
+            };
+            for inst in response.auto_scaling_instances.unwrap_or_default() {
+                details.insert(inst.instance_id, (inst.auto_scaling_group_name, inst.lifecycle_state));
+            }
+            next_token = response.next_token.filter(|token| !token.is_empty());
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok(details)
+    }
+
+    // Reads a json list of `AshufInfo` (e.g. the output of `aal`) from a file,
+    // and returns the instances whose availability zone is in the requested
+    // region.  The account isn't recorded per-instance, so it's ignored, and
+    // so are the filters.
+    pub struct FixtureProvider {
+        pub path: String,
+    }
+
+    impl InstanceProvider for FixtureProvider {
+        fn instances(&self, _account: &String, region_name: &String, _filters: &Vec<InstanceFilter>) -> Result<Vec<AshufInfo>, String> {
+            let mut file_bytes = String::new();
+            let mut fixture_file = match File::open(Path::new(&self.path)) {
+                Ok(file) => file,
+                Err(err) => return Err(format!("Couldn't read fixture file {} because {:?}", self.path, err))
+            };
+            if let Err(err) = fixture_file.read_to_string(&mut file_bytes) {
+                return Err(format!("Couldn't read fixture file {} because {:?}", self.path, err));
+            }
+            let instances: Vec<AshufInfo> = match serde_json::from_str(&file_bytes) {
+                Ok(instances) => instances,
+                Err(err) => return Err(format!("Couldn't parse fixture file {} because {:?}", self.path, err))
+            };
+            Ok(instances
+               .into_iter()
+               .filter(|inst| inst.availability_zone.starts_with(region_name.as_str()))
+               .map(|mut inst| {
+                   // Fixtures written before asg_name existed still have the tag
+                   if inst.asg_name.is_none() {
+                       inst.asg_name = inst.tags.get(ASG_TAG).cloned();
+                   }
+                   inst
+               })
+               .collect())
+        }
+    }
+
+
+    // With no filters this is a full refresh, and the result replaces the
+    // cache.  A filtered result is only part of the account, so it is returned
+    // without clobbering the cached data.
+    pub fn read_without_cache(provider: &dyn InstanceProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, filters: &Vec<InstanceFilter>, settings: &CacheSettings) -> Vec<AshufInfo> {
+        if filters.is_empty() {
+            let key = CacheKey::new(aws_account_id, region_name, AshufInfo::API);
+            return cache::read_without_cache(cache_dir, &key, settings, || provider.instances(aws_account_id, region_name, filters));
+        }
+        match provider.instances(aws_account_id, region_name, filters) {
+            Ok(instances_data) => instances_data,
+            Err(error) => {
+                eprintln!("{}", error);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn read_via_cache(provider: &dyn InstanceProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<AshufInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, AshufInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.instances(aws_account_id, region_name, &Vec::new()))
+    }
+
+    // Every region's instances, through the cache unless it's bypassed.
+    // Filtered results are only part of a region, so they never go in the
+    // cache.
+    pub fn read_regions(provider: Arc<dyn InstanceProvider>, scope: &CacheScope, filters: &Vec<InstanceFilter>) -> Vec<AshufInfo> {
+        cache::report_failures(try_read_regions(provider, scope, filters))
+    }
+
+    // The same, along with why each region that failed did
+    pub fn try_read_regions(provider: Arc<dyn InstanceProvider>, scope: &CacheScope, filters: &Vec<InstanceFilter>) -> (Vec<AshufInfo>, Vec<String>) {
+        let account = scope.account.clone();
+        if scope.bypass_cache && !filters.is_empty() {
+            let filters = filters.clone();
+            return cache::read_each_region(&scope.regions, move |region_name| provider.instances(&account, region_name, &filters));
+        }
+        cache::try_read_regions(scope, move |region_name| provider.instances(&account, region_name, &Vec::new()))
+    }
+
+    // The matching instances for one check of `aal --watch`, or why some
+    // region couldn't be read.  A region with nothing left in it is news,
+    // but one that couldn't be read isn't.
+    pub fn poll_matching(provider: Arc<dyn InstanceProvider>, scope: &CacheScope, filters: &Vec<InstanceFilter>, pattern: &String, tags: &Vec<String>) -> Result<Vec<AshufInfo>, String> {
+        let (found, failures) = try_read_regions(provider, scope, filters);
+        if !failures.is_empty() {
+            return Err(failures.join("\n"));
+        }
+        Ok(instances_matching_regex(pattern.clone(), tags.clone(), found))
+    }
+
+    // The instances whose tags match the pattern, read the way the scope's
+    // cache policy says (see cache::read_matching)
+    pub fn read_matching(provider: Arc<dyn InstanceProvider>, scope: &CacheScope, filters: &Vec<InstanceFilter>, pattern: &String, tags: &Vec<String>) -> Vec<AshufInfo> {
+        cache::read_matching(scope, pattern, tags, || read_regions(provider, scope, filters))
+    }
+
+    // Only the regions that match the pattern.  An empty pattern matches all of them.
+    pub fn regions_matching(pattern: &String, regions: Vec<String>) -> Vec<String> {
+        let rexpr = Regex::new(pattern).unwrap();
+        regions.into_iter()
+            .filter(|r| rexpr.is_match(r))
+            .collect::<Vec<String>>()
+    }
+
+
+    pub fn ip_addresses_of(instance: &Instance) -> (Vec<String>, Vec<String>) {
+        // A host can have either an ENI in vpc, or a private IP address from an EIP (classic)
+        // This function extracts those addresses, and returns two vectors.  The left
+        // vector contains the private addresses of an instance, and the right vector contains the
+        // public addresses of an instance.
+        //
+        // The primary address comes first, since that's the one to ssh to,
+        // and the other ENIs' addresses follow in order, so that the same
+        // instance always gets the same list.
+        let mut private = Vec::new();
+        if let Some(ref network_interfaces) = instance.network_interfaces {
+            for interface in network_interfaces {
+                if let Some(ref addr) = interface.private_ip_address {
+                    private.push(addr.clone());
+                }
+            }
+        }
+        private.sort();
+        private.dedup();
+        if let Some(ref primary) = instance.private_ip_address {
+            private.retain(|addr| addr != primary);
+            private.insert(0, primary.clone());
+        }
+
+        let public = instance.public_ip_address.iter().cloned().collect();
+        (private, public)
+    }
+
+    pub fn tags_of(instance: &Instance) -> HashMap<String, String> {
+        // Tags are stored as inconvenient pairs of {"Name": "name", "Value": "Value"}
+        // turn them into simpler key/value map here
+        let mut tags = HashMap::new();
+        if let Some(ref instance_tags) = instance.tags {
+            for tag in instance_tags {
+                if let (Some(key), Some(val)) = (&tag.key, &tag.value) {
+                    tags.insert(key.clone(), val.clone());
+                }
+
+            }
+        }
+        tags
+    }
+    
+
+    pub fn ashuf_info_list(instances: Vec<Instance>) -> Vec<AshufInfo> {
+        // Take just the data we want for the AshufInfo struct from the
+        // rusoto::ec2::Instance type, and return a vector of `AshufInfo`
+        //
+        // All data is copied from the instances provided, they are consumed
+        // here.
+        let mut limited_instances: Vec<AshufInfo> = Vec::new();
+        for inst in instances {
+            // println!("This instance is {:?}",  inst);
+            let (private_addrs, public_addrs) = ip_addresses_of(&inst);
+            let tags = tags_of(&inst);
+            // println!("{:?}", addrs);
+            let new_asi = AshufInfo {
+                instance_id: inst.instance_id.unwrap(),
+                private_ip_addresses: private_addrs,
+                public_ip_addresses: public_addrs,
+                state_name: inst.state.unwrap().name.unwrap(),
+                launch_time: inst.launch_time.unwrap(),
+                availability_zone: inst.placement.unwrap().availability_zone.unwrap(),
+                image_ami: inst.image_id.unwrap(),
+                asg_name: tags.get(ASG_TAG).cloned(),
+                lifecycle_state: None,
+                tags: tags,
+                region: String::new(),
+            };
+            limited_instances.push(new_asi);
+        }
+        limited_instances
+    }
+    
+
+    // Anything that the regex matching can be used on.  For ec2 instances the
+    // fields are tags, other resources can offer whatever names make sense.
+    pub trait Searchable {
+        fn field(&self, name: &String) -> Option<&String>;
+
+        // Whether a match in an old cache is good enough for cache_first
+        // (see cache::read_matching)
+        fn is_usable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Searchable for AshufInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            if name == ASG_TAG {
+                self.asg_name.as_ref()
+            } else {
+                self.tags.get(name)
+            }
+        }
+
+        // A stopped instance may have been started since, or be long gone
+        fn is_usable(&self) -> bool {
+            self.state_name == "running"
+        }
+    }
+
+    // returns OK on the left, and Not OK on the right.
+    // Let's define that so that on the left are matched instances,
+    // and the right  is unmatched instances.
+    pub fn partition_matches<T: Searchable>(rexpr: &Regex, tag: &String, instances: Vec<T>) -> (Vec<T>,  Vec<T>) {
+        let (matched, unmatched) = instances
+            .into_iter()
+            .partition(|inst| {
+                if let Some(tval) = inst.field(tag) {
+                    if rexpr.is_match(tval) {  // Match on the value (assuming it can't be None?)
+                        // println!("Matched {:?}", tval);
+                        true
+                    } else {
+                        false
+                    }
+                } else {
+                    false
+                }
+            });
+        (matched, unmatched)
+    }
+    
+
+    pub fn instances_matching_regex<T: Searchable + Clone>(pattern: String, interesting_tags: Vec<String>, instances: Vec<T>) -> Vec<T> {
+        let rexpr = Regex::new(&pattern).unwrap();
+        let mut unmatched_instances = Vec::new();
+        let mut matched_instances = Vec::new();
+        unmatched_instances.extend_from_slice(instances.as_slice());
+
+        for ref tag in interesting_tags {
+            let (m, u) = partition_matches(&rexpr, tag, unmatched_instances);
+            // Re-bind unmatched instances for the next loop
+            unmatched_instances = u;
+            matched_instances.extend_from_slice(m.as_slice());
+        }
+        matched_instances
+    }
+
+    // Just the instances with these ids, e.g. the targets of a load balancer
+    pub fn instances_with_ids(ids: &HashSet<String>, instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
+        instances.into_iter()
+            .filter(|i| ids.contains(&i.instance_id))
+            .collect::<Vec<AshufInfo>>()
+    }
+
+    // Leave out instances that autoscaling is taking out of service.  When the
+    // lifecycle state isn't known, the instance is kept.
+    pub fn in_service_instances(instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
+        instances.into_iter()
+            .filter(|i| match i.lifecycle_state {
+                Some(ref state) => !(state.starts_with("Terminating") || state == "Standby"),
+                None => true,
+            })
+            .collect::<Vec<AshufInfo>>()
+    }
+
+    // Just the instances with any of these private or public addresses
+    pub fn instances_with_ips(ips: &HashSet<String>, instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
+        instances.into_iter()
+            .filter(|i| i.private_ip_addresses.iter().chain(i.public_ip_addresses.iter()).any(|ip| !ip.is_empty() && ips.contains(ip)))
+            .collect::<Vec<AshufInfo>>()
+    }
+
+    pub fn running_instances(instances: Vec<AshufInfo>) -> Vec<AshufInfo> {
+        instances.into_iter()
+            .filter(|i| i.state_name == "running")
+            .collect::<Vec<AshufInfo>>()
+    }
+
+    pub fn ec2_res_to_instances(reservations: Vec<Reservation>) -> Vec<Instance> {
+        // The ec2 `describe-instances` call returns a structure that describe
+        // reservations, and the reservations contain instances.
+        // Since I pretty much never, ever, ever need to know about reservations,
+        // and always care about instances, I am removing the reservations info
+        // and creating a vector of instances.
+
+
+        let mut instances = Vec::new();
+        for res in reservations {
+            if let Some(res_instances) = res.instances {
+                instances.extend(res_instances);
+            }
+        }
+        instances
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use std::collections::HashMap;
+        use std::env;
+        use std::fs::{File, create_dir_all, read_dir, remove_dir_all, remove_file};
+        use std::io::Write;
+        use std::process;
+
+        use rusoto_ec2::InstanceNetworkInterface;
+
+        use super::*;
+        use cache::{CachePolicy, CacheSettings};
+
+        fn filter_values(filters: &Vec<InstanceFilter>) -> Vec<(String, Vec<String>)> {
+            filters.iter().map(|f| (f.name.clone(), f.values.clone())).collect()
+        }
+
+        #[test]
+        fn literal_patterns_become_tag_filters() {
+            let tags = vec!["Name".to_string(), "Tier".to_string()];
+            assert_eq!(filter_values(&pattern_filters(&"web".to_string(), &tags, false)),
+                       vec![("tag-value".to_string(), vec!["*web*".to_string()])]);
+            let name_only = vec!["Name".to_string()];
+            assert_eq!(filter_values(&pattern_filters(&"^web".to_string(), &name_only, false)),
+                       vec![("tag:Name".to_string(), vec!["web*".to_string()])]);
+        }
+
+        #[test]
+        fn regex_patterns_are_left_to_the_client() {
+            let tags = vec!["Name".to_string(), "Tier".to_string()];
+            assert!(pattern_filters(&"web-[0-9]+".to_string(), &tags, false).is_empty());
+            assert!(pattern_filters(&"^".to_string(), &tags, false).is_empty());
+            assert_eq!(filter_values(&pattern_filters(&"web.*".to_string(), &tags, true)),
+                       vec![("instance-state-name".to_string(), vec!["running".to_string()])]);
+        }
+
+        // An instance named web-<id>, with one private address, for other
+        // modules' tests too
+        pub fn instance(id: &str, availability_zone: &str) -> AshufInfo {
+            let mut tags = HashMap::new();
+            tags.insert("Name".to_string(), format!("web-{}", id));
+            AshufInfo {
+                instance_id: id.to_string(),
+                private_ip_addresses: vec!["10.0.0.1".to_string()],
+                public_ip_addresses: Vec::new(),
+                state_name: "running".to_string(),
+                launch_time: "2019-06-01T12:00:00.000Z".to_string(),
+                availability_zone: availability_zone.to_string(),
+                image_ami: "ami-1".to_string(),
+                tags: tags,
+                region: String::new(),
+                asg_name: None,
+                lifecycle_state: None,
+            }
+        }
+
+        #[test]
+        fn every_address_is_its_own_entry() {
+            let interface = |addr: &str| InstanceNetworkInterface {
+                private_ip_address: Some(addr.to_string()),
+                ..Default::default()
+            };
+            let multi_eni = Instance {
+                private_ip_address: Some("10.0.0.5".to_string()),
+                network_interfaces: Some(vec![interface("10.0.0.9"), interface("10.0.0.5"), interface("10.0.0.7")]),
+                ..Default::default()
+            };
+            assert_eq!(ip_addresses_of(&multi_eni),
+                       (vec!["10.0.0.5".to_string(), "10.0.0.7".to_string(), "10.0.0.9".to_string()], Vec::new()));
+
+            let with_public = Instance {
+                private_ip_address: Some("10.0.0.5".to_string()),
+                public_ip_address: Some("54.0.0.5".to_string()),
+                ..Default::default()
+            };
+            assert_eq!(ip_addresses_of(&with_public), (vec!["10.0.0.5".to_string()], vec!["54.0.0.5".to_string()]));
+        }
+
+        #[test]
+        fn fixture_instances_stay_out_of_the_cache() {
+            let dir = env::temp_dir().join(format!("raal_fixture_{}", process::id()));
+            let _ = remove_dir_all(&dir);
+            create_dir_all(&dir).unwrap();
+            let cache_dir = dir.to_string_lossy().into_owned();
+            let fixture = format!("{}/fixture.json", cache_dir);
+            let instances = vec![instance("i-1", "us-east-1a"), instance("i-2", "us-west-2b")];
+            File::create(&fixture).unwrap()
+                .write_all(serde_json::to_string(&instances).unwrap().as_bytes()).unwrap();
+
+            let provider = FixtureProvider { path: fixture.clone() };
+            let settings = CacheSettings { policy: CachePolicy::Uncached, ..Default::default() };
+            let (account, region_name) = ("123".to_string(), "us-east-1".to_string());
+            let found = read_via_cache(&provider, &cache_dir, &region_name, &account, &settings);
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].instance_id, "i-1");
+            let refreshed = read_without_cache(&provider, &cache_dir, &region_name, &account, &Vec::new(), &settings);
+            assert_eq!(refreshed.len(), 1);
+
+            // Only the fixture was ever there
+            remove_file(&fixture).unwrap();
+            assert_eq!(read_dir(&dir).unwrap().count(), 0);
+            remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn polls_tell_an_empty_region_from_a_failed_one() {
+            let dir = env::temp_dir().join(format!("raal_poll_{}", process::id()));
+            let _ = remove_dir_all(&dir);
+            create_dir_all(&dir).unwrap();
+            let cache_dir = dir.to_string_lossy().into_owned();
+            let fixture = format!("{}/fixture.json", cache_dir);
+            File::create(&fixture).unwrap()
+                .write_all(serde_json::to_string(&vec![instance("i-1", "us-west-2a")]).unwrap().as_bytes()).unwrap();
+            let scope = CacheScope {
+                cache_dir: cache_dir.clone(),
+                account: "123".to_string(),
+                regions: vec!["us-east-1".to_string()],
+                bypass_cache: false,
+                settings: CacheSettings { policy: CachePolicy::Uncached, ..Default::default() },
+            };
+            let (pattern, tags) = ("web".to_string(), vec!["Name".to_string()]);
+
+            let provider = Arc::new(FixtureProvider { path: fixture.clone() });
+            assert!(poll_matching(provider, &scope, &Vec::new(), &pattern, &tags).unwrap().is_empty());
+            let missing = Arc::new(FixtureProvider { path: format!("{}/missing.json", cache_dir) });
+            assert!(poll_matching(missing, &scope, &Vec::new(), &pattern, &tags).is_err());
+            remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+
+// A database is found the same way as an instance, but what we want from it
+// is the endpoint, port and engine so the right client can be started.
+pub mod rds_instances {
+    use rusoto_rds::{Rds, RdsClient, DescribeDBInstancesMessage, DBInstance};
+
+    use std::sync::Arc;
+
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+    use ec2_instances::Searchable;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct RdsInfo {
+        pub identifier: String,
+        pub engine: String,
+        pub endpoint: String,
+        pub port: i64,
+        pub status: String,
+        pub availability_zone: String,
+        pub db_name: String,
+        pub master_username: String,
+        #[serde(default)]
+        pub region: String,
+    }
+
+    // Databases don't get tags in the describe call, so match on the
+    // identifier and the engine instead
+    impl Searchable for RdsInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            match name.as_str() {
+                "identifier" => Some(&self.identifier),
+                "engine" => Some(&self.engine),
+                _ => None,
+            }
+        }
+    }
+
+    impl CachedResource for RdsInfo {
+        const API: &'static str = "rds_instances";
+    }
+
+    impl Regional for RdsInfo {
+        fn set_region(&mut self, region_name: &String) {
+            self.region = region_name.clone();
+        }
+    }
+
+    pub trait DbInstanceProvider: Send + Sync {
+        fn db_instances(&self, account: &String, region_name: &String) -> Result<Vec<RdsInfo>, String>;
+    }
+
+    // Calls describe-db-instances with the environment's credentials,
+    // following the marker until every page has been read.
+    pub struct RdsProvider {
+        pub environ: EnvironConfig,
+    }
+
+    impl RdsProvider {
+        pub fn from_config(environ: &EnvironConfig) -> RdsProvider {
+            RdsProvider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl DbInstanceProvider for RdsProvider {
+        fn db_instances(&self, account: &String, region_name: &String) -> Result<Vec<RdsInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, region_name, account)?;
+            let client = RdsClient::new_with(http_client()?, credentials, reg);
+
+            let mut databases = Vec::new();
+            let mut marker = None;
+            loop {
+                let rds_request_input = DescribeDBInstancesMessage {
+                    marker: marker,
+                    ..Default::default()
+                };
+                match client.describe_db_instances(rds_request_input).sync() {
+                    Ok(response) => {
+                        databases.extend(rds_info_list(response.db_instances.unwrap_or(Vec::new())));
+                        marker = response.marker.filter(|m| !m.is_empty());
+                        if marker.is_none() {
+                            break;
+                        }
+                    },
+                    Err(error) => return Err(format!("Error when invoking describe_db_instances: {:?}", error))
+                }
+            }
+            Ok(databases)
+        }
+    }
+
+    // Like ashuf_info_list, keep just what's needed to connect.  A database
+    // that's still being created has no endpoint yet, and is left out.
+    pub fn rds_info_list(db_instances: Vec<DBInstance>) -> Vec<RdsInfo> {
+        let mut limited_databases = Vec::new();
+        for db in db_instances {
+            let (address, port) = match db.endpoint {
+                Some(endpoint) => match (endpoint.address, endpoint.port) {
+                    (Some(address), Some(port)) => (address, port),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            limited_databases.push(RdsInfo {
+                identifier: db.db_instance_identifier.unwrap_or_default(),
+                engine: db.engine.unwrap_or_default(),
+                endpoint: address,
+                port: port,
+                status: db.db_instance_status.unwrap_or_default(),
+                availability_zone: db.availability_zone.unwrap_or_default(),
+                db_name: db.db_name.unwrap_or_default(),
+                master_username: db.master_username.unwrap_or_default(),
+                region: String::new(),
+            });
+        }
+        limited_databases
+    }
+
+    pub fn available_instances(databases: Vec<RdsInfo>) -> Vec<RdsInfo> {
+        databases.into_iter()
+            .filter(|db| db.status == "available")
+            .collect::<Vec<RdsInfo>>()
+    }
+
+    pub fn read_without_cache(provider: &dyn DbInstanceProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<RdsInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, RdsInfo::API);
+        cache::read_without_cache(cache_dir, &key, settings, || provider.db_instances(aws_account_id, region_name))
+    }
+
+    pub fn read_via_cache(provider: &dyn DbInstanceProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<RdsInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, RdsInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.db_instances(aws_account_id, region_name))
+    }
+
+    pub fn read_regions(provider: Arc<dyn DbInstanceProvider>, scope: &CacheScope) -> Vec<RdsInfo> {
+        let account = scope.account.clone();
+        cache::read_regions(scope, move |region_name| provider.db_instances(&account, region_name))
+    }
+}
+
+// Every node of every cache cluster, so that a random node of a tier can be
+// picked and poked at with redis-cli or the memcached tools.
+pub mod elasticache_nodes {
+    use rusoto_elasticache::{ElastiCache, ElastiCacheClient, DescribeCacheClustersMessage, CacheCluster};
+
+    use std::sync::Arc;
+
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+    use ec2_instances::Searchable;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct CacheNodeInfo {
+        pub cluster_id: String,
+        pub node_id: String,
+        pub engine: String,
+        pub endpoint: String,
+        pub port: i64,
+        pub status: String,
+        pub availability_zone: String,
+        #[serde(default)]
+        pub region: String,
+    }
+
+    impl Searchable for CacheNodeInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            match name.as_str() {
+                "cluster_id" => Some(&self.cluster_id),
+                "engine" => Some(&self.engine),
+                _ => None,
+            }
+        }
+    }
+
+    impl CachedResource for CacheNodeInfo {
+        const API: &'static str = "elasticache_nodes";
+    }
+
+    impl Regional for CacheNodeInfo {
+        fn set_region(&mut self, region_name: &String) {
+            self.region = region_name.clone();
+        }
+    }
+
+    pub trait CacheNodeProvider: Send + Sync {
+        fn cache_nodes(&self, account: &String, region_name: &String) -> Result<Vec<CacheNodeInfo>, String>;
+    }
+
+    // Calls describe-cache-clusters, with node info, following the marker
+    // until every page has been read.
+    pub struct ElastiCacheProvider {
+        pub environ: EnvironConfig,
+    }
+
+    impl ElastiCacheProvider {
+        pub fn from_config(environ: &EnvironConfig) -> ElastiCacheProvider {
+            ElastiCacheProvider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl CacheNodeProvider for ElastiCacheProvider {
+        fn cache_nodes(&self, account: &String, region_name: &String) -> Result<Vec<CacheNodeInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, region_name, account)?;
+            let client = ElastiCacheClient::new_with(http_client()?, credentials, reg);
+
+            let mut nodes = Vec::new();
+            let mut marker = None;
+            loop {
+                let request_input = DescribeCacheClustersMessage {
+                    show_cache_node_info: Some(true),
+                    marker: marker,
+                    ..Default::default()
+                };
+                match client.describe_cache_clusters(request_input).sync() {
+                    Ok(response) => {
+                        nodes.extend(cache_node_info_list(response.cache_clusters.unwrap_or(Vec::new())));
+                        marker = response.marker.filter(|m| !m.is_empty());
+                        if marker.is_none() {
+                            break;
+                        }
+                    },
+                    Err(error) => return Err(format!("Error when invoking describe_cache_clusters: {:?}", error))
+                }
+            }
+            Ok(nodes)
+        }
+    }
+
+    // Flatten clusters into their nodes.  A node without an endpoint can't be
+    // connected to, so it's left out.
+    pub fn cache_node_info_list(clusters: Vec<CacheCluster>) -> Vec<CacheNodeInfo> {
+        let mut limited_nodes = Vec::new();
+        for cluster in clusters {
+            let cluster_id = cluster.cache_cluster_id.unwrap_or_default();
+            let engine = cluster.engine.unwrap_or_default();
+            for node in cluster.cache_nodes.unwrap_or(Vec::new()) {
+                let (address, port) = match node.endpoint {
+                    Some(endpoint) => match (endpoint.address, endpoint.port) {
+                        (Some(address), Some(port)) => (address, port),
+                        _ => continue,
+                    },
+                    None => continue,
+                };
+                limited_nodes.push(CacheNodeInfo {
+                    cluster_id: cluster_id.clone(),
+                    node_id: node.cache_node_id.unwrap_or_default(),
+                    engine: engine.clone(),
+                    endpoint: address,
+                    port: port,
+                    status: node.cache_node_status.unwrap_or_default(),
+                    availability_zone: node.customer_availability_zone.unwrap_or_default(),
+                    region: String::new(),
+                });
+            }
+        }
+        limited_nodes
+    }
+
+    pub fn available_nodes(nodes: Vec<CacheNodeInfo>) -> Vec<CacheNodeInfo> {
+        nodes.into_iter()
+            .filter(|node| node.status == "available")
+            .collect::<Vec<CacheNodeInfo>>()
+    }
+
+    pub fn read_without_cache(provider: &dyn CacheNodeProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<CacheNodeInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, CacheNodeInfo::API);
+        cache::read_without_cache(cache_dir, &key, settings, || provider.cache_nodes(aws_account_id, region_name))
+    }
+
+    pub fn read_via_cache(provider: &dyn CacheNodeProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<CacheNodeInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, CacheNodeInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.cache_nodes(aws_account_id, region_name))
+    }
+
+    pub fn read_regions(provider: Arc<dyn CacheNodeProvider>, scope: &CacheScope) -> Vec<CacheNodeInfo> {
+        let account = scope.account.clone();
+        cache::read_regions(scope, move |region_name| provider.cache_nodes(&account, region_name))
+    }
+}
+
+// Load balancers of every flavor, along with the targets behind them and
+// whether those targets are healthy.  The targets are usually ec2 instances,
+// which is how `ashuf --lb` finds a backend to ssh to.
+pub mod load_balancers {
+    use rusoto_elb::{Elb, ElbClient, DescribeAccessPointsInput, DescribeEndPointStateInput};
+    use rusoto_elbv2::{Elb as ElbV2, ElbClient as ElbV2Client, DescribeLoadBalancersInput, DescribeTargetGroupsInput, DescribeTargetHealthInput};
+
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+    use ec2_instances::Searchable;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct LoadBalancerInfo {
+        pub name: String,
+        // "application", "network", or "classic"
+        pub kind: String,
+        pub dns_name: String,
+        pub scheme: String,
+        pub targets: Vec<TargetInfo>,
+        #[serde(default)]
+        pub region: String,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct TargetInfo {
+        // Empty for a classic load balancer, which has no target groups
+        pub target_group: String,
+        // An instance id, or an ip address for ip targets
+        pub id: String,
+        pub port: Option<i64>,
+        pub health: String,
+    }
+
+    impl TargetInfo {
+        // Target groups say "healthy", classic load balancers say "InService"
+        pub fn is_healthy(&self) -> bool {
+            self.health == "healthy" || self.health == "InService"
+        }
+    }
+
+    impl Searchable for LoadBalancerInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            match name.as_str() {
+                "name" => Some(&self.name),
+                "dns_name" => Some(&self.dns_name),
+                _ => None,
+            }
+        }
+    }
+
+    impl CachedResource for LoadBalancerInfo {
+        const API: &'static str = "load_balancers";
+    }
+
+    impl Regional for LoadBalancerInfo {
+        fn set_region(&mut self, region_name: &String) {
+            self.region = region_name.clone();
+        }
+    }
+
+    pub trait LoadBalancerProvider: Send + Sync {
+        fn load_balancers(&self, account: &String, region_name: &String) -> Result<Vec<LoadBalancerInfo>, String>;
+    }
+
+    // Reads both the elbv2 (application and network) and the classic elb APIs
+    pub struct ElbProvider {
+        pub environ: EnvironConfig,
+    }
+
+    impl ElbProvider {
+        pub fn from_config(environ: &EnvironConfig) -> ElbProvider {
+            ElbProvider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl LoadBalancerProvider for ElbProvider {
+        fn load_balancers(&self, account: &String, region_name: &String) -> Result<Vec<LoadBalancerInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, region_name, account)?;
+            let v2_client = ElbV2Client::new_with(http_client()?, credentials.clone(), reg.clone());
+            let classic_client = ElbClient::new_with(http_client()?, credentials, reg);
+
+            let mut lbs = v2_load_balancers(&v2_client)?;
+            lbs.extend(classic_load_balancers(&classic_client)?);
+            Ok(lbs)
+        }
+    }
+
+    fn v2_load_balancers(client: &ElbV2Client) -> Result<Vec<LoadBalancerInfo>, String> {
+        let mut lbs = Vec::new();
+        let mut marker = None;
+        loop {
+            let request_input = DescribeLoadBalancersInput {
+                marker: marker,
+                ..Default::default()
+            };
+            let response = match client.describe_load_balancers(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking describe_load_balancers: {:?}", error))
+            };
+            for lb in response.load_balancers.unwrap_or(Vec::new()) {
+                let targets = match lb.load_balancer_arn {
+                    Some(ref arn) => v2_targets(client, arn)?,
+                    None => Vec::new(),
+                };
+                lbs.push(LoadBalancerInfo {
+                    name: lb.load_balancer_name.unwrap_or_default(),
+                    kind: lb.type_.unwrap_or_default(),
+                    dns_name: lb.dns_name.unwrap_or_default(),
+                    scheme: lb.scheme.unwrap_or_default(),
+                    targets: targets,
+                    region: String::new(),
+                });
+            }
+            marker = response.next_marker.filter(|m| !m.is_empty());
+            if marker.is_none() {
+                break;
+            }
+        }
+        Ok(lbs)
+    }
+
+    // Every target of every target group attached to the load balancer
+    fn v2_targets(client: &ElbV2Client, lb_arn: &String) -> Result<Vec<TargetInfo>, String> {
+        let mut targets = Vec::new();
+        let mut marker = None;
+        loop {
+            let request_input = DescribeTargetGroupsInput {
+                load_balancer_arn: Some(lb_arn.clone()),
+                marker: marker,
+                ..Default::default()
+            };
+            let response = match client.describe_target_groups(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking describe_target_groups: {:?}", error))
+            };
+            for group in response.target_groups.unwrap_or(Vec::new()) {
+                let group_arn = match group.target_group_arn {
+                    Some(arn) => arn,
+                    None => continue,
+                };
+                let group_name = group.target_group_name.unwrap_or_default();
+                let health_input = DescribeTargetHealthInput {
+                    target_group_arn: group_arn,
+                    targets: None,
+                };
+                let health = match client.describe_target_health(health_input).sync() {
+                    Ok(health) => health,
+                    Err(error) => return Err(format!("Error when invoking describe_target_health: {:?}", error))
+                };
+                for description in health.target_health_descriptions.unwrap_or(Vec::new()) {
+                    if let Some(target) = description.target {
+                        targets.push(TargetInfo {
+                            target_group: group_name.clone(),
+                            id: target.id,
+                            port: target.port,
+                            health: description.target_health
+                                .and_then(|h| h.state)
+                                .unwrap_or_default(),
+                        });
+                    }
+                }
+            }
+            marker = response.next_marker.filter(|m| !m.is_empty());
+            if marker.is_none() {
+                break;
+            }
+        }
+        Ok(targets)
+    }
+
+    fn classic_load_balancers(client: &ElbClient) -> Result<Vec<LoadBalancerInfo>, String> {
+        let mut lbs = Vec::new();
+        let mut marker = None;
+        loop {
+            let request_input = DescribeAccessPointsInput {
+                marker: marker,
+                ..Default::default()
+            };
+            let response = match client.describe_load_balancers(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking describe_load_balancers (classic): {:?}", error))
+            };
+            for lb in response.load_balancer_descriptions.unwrap_or(Vec::new()) {
+                let name = lb.load_balancer_name.unwrap_or_default();
+                let health_input = DescribeEndPointStateInput {
+                    instances: None,
+                    load_balancer_name: name.clone(),
+                };
+                let health = match client.describe_instance_health(health_input).sync() {
+                    Ok(health) => health,
+                    Err(error) => return Err(format!("Error when invoking describe_instance_health: {:?}", error))
+                };
+                let targets = health.instance_states
+                    .unwrap_or(Vec::new())
+                    .into_iter()
+                    .map(|state| TargetInfo {
+                        target_group: String::new(),
+                        id: state.instance_id.unwrap_or_default(),
+                        port: None,
+                        health: state.state.unwrap_or_default(),
+                    })
+                    .collect();
+                lbs.push(LoadBalancerInfo {
+                    name: name,
+                    kind: "classic".to_string(),
+                    dns_name: lb.dns_name.unwrap_or_default(),
+                    scheme: lb.scheme.unwrap_or_default(),
+                    targets: targets,
+                    region: String::new(),
+                });
+            }
+            marker = response.next_marker.filter(|m| !m.is_empty());
+            if marker.is_none() {
+                break;
+            }
+        }
+        Ok(lbs)
+    }
+
+    // The same load balancers, with their unhealthy targets dropped
+    pub fn healthy_only(lbs: Vec<LoadBalancerInfo>) -> Vec<LoadBalancerInfo> {
+        lbs.into_iter()
+            .map(|mut lb| {
+                lb.targets.retain(|t| t.is_healthy());
+                lb
+            })
+            .collect()
+    }
+
+    // The ids (or addresses) of every healthy target.  Classic load balancers
+    // can report an instance without its id, which is left out rather than
+    // matching everything without an address.
+    pub fn healthy_target_ids(lbs: &Vec<LoadBalancerInfo>) -> HashSet<String> {
+        let mut ids = HashSet::new();
+        for lb in lbs {
+            for target in &lb.targets {
+                if target.is_healthy() && !target.id.is_empty() {
+                    ids.insert(target.id.clone());
+                }
+            }
+        }
+        ids
+    }
+
+    pub fn read_without_cache(provider: &dyn LoadBalancerProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<LoadBalancerInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, LoadBalancerInfo::API);
+        cache::read_without_cache(cache_dir, &key, settings, || provider.load_balancers(aws_account_id, region_name))
+    }
+
+    pub fn read_via_cache(provider: &dyn LoadBalancerProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<LoadBalancerInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, LoadBalancerInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.load_balancers(aws_account_id, region_name))
+    }
+
+    pub fn read_regions(provider: Arc<dyn LoadBalancerProvider>, scope: &CacheScope) -> Vec<LoadBalancerInfo> {
+        let account = scope.account.clone();
+        cache::read_regions(scope, move |region_name| provider.load_balancers(&account, region_name))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn target(id: &str, health: &str) -> TargetInfo {
+            TargetInfo {
+                target_group: String::new(),
+                id: id.to_string(),
+                port: None,
+                health: health.to_string(),
+            }
+        }
+
+        #[test]
+        fn only_healthy_targets_with_ids() {
+            let lb = LoadBalancerInfo {
+                name: "web".to_string(),
+                kind: "classic".to_string(),
+                dns_name: "web-123.us-east-1.elb.amazonaws.com".to_string(),
+                scheme: "internal".to_string(),
+                targets: vec![target("i-1", "InService"), target("i-2", "OutOfService"), target("", "InService"), target("10.0.0.3", "healthy")],
+                region: "us-east-1".to_string(),
+            };
+            let mut ids = healthy_target_ids(&vec![lb]).into_iter().collect::<Vec<String>>();
+            ids.sort();
+            assert_eq!(ids, vec!["10.0.0.3".to_string(), "i-1".to_string()]);
+        }
+    }
+}
+
+// Running ECS tasks, with enough about the container instance they're on
+// (its ec2 id and private ip) to ssh there and get into the container.
+// Fargate tasks have no container instance to ssh to, so they're left out.
+pub mod ecs_tasks {
+    use rusoto_ecs::{Ecs, EcsClient, ListClustersRequest, ListTasksRequest, DescribeTasksRequest, DescribeContainerInstancesRequest, Task};
+    use rusoto_ec2::Ec2Client;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+    use ec2_instances::{Searchable, InstanceFilter, describe_instance_list};
+
+    // describe-tasks and describe-container-instances take at most 100 at a time
+    const DESCRIBE_BATCH: usize = 100;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct EcsTaskInfo {
+        pub cluster: String,
+        // Empty for a task that wasn't started by a service
+        pub service: String,
+        pub task_arn: String,
+        // family:revision
+        pub task_definition: String,
+        pub last_status: String,
+        pub containers: Vec<EcsContainerInfo>,
+        pub ec2_instance_id: String,
+        pub private_ip: String,
+        #[serde(default)]
+        pub region: String,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct EcsContainerInfo {
+        pub name: String,
+        // The docker container id on the host
+        pub runtime_id: String,
+    }
+
+    impl Searchable for EcsTaskInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            match name.as_str() {
+                "service" => Some(&self.service),
+                "task_definition" => Some(&self.task_definition),
+                "cluster" => Some(&self.cluster),
+                _ => None,
+            }
+        }
+    }
+
+    impl CachedResource for EcsTaskInfo {
+        const API: &'static str = "ecs_tasks";
+    }
+
+    impl Regional for EcsTaskInfo {
+        fn set_region(&mut self, region_name: &String) {
+            self.region = region_name.clone();
+        }
+    }
+
+    pub trait TaskProvider: Send + Sync {
+        fn tasks(&self, account: &String, region_name: &String) -> Result<Vec<EcsTaskInfo>, String>;
+    }
+
+    // Walks every cluster's running tasks, then looks up the container
+    // instances they're on, and then the private ips of those instances.
+    pub struct EcsProvider {
+        pub environ: EnvironConfig,
+    }
+
+    impl EcsProvider {
+        pub fn from_config(environ: &EnvironConfig) -> EcsProvider {
+            EcsProvider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl TaskProvider for EcsProvider {
+        fn tasks(&self, account: &String, region_name: &String) -> Result<Vec<EcsTaskInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, region_name, account)?;
+            let client = EcsClient::new_with(http_client()?, credentials.clone(), reg.clone());
+
+            let mut tasks = Vec::new();
+            for cluster_arn in list_clusters(&client)? {
+                tasks.extend(cluster_tasks(&client, &cluster_arn)?);
+            }
+
+            // Fill in the private ips from ec2, which is asked for just these
+            // instances, with the same credentials
+            let mut instance_ids = tasks.iter()
+                .map(|t| t.ec2_instance_id.clone())
+                .collect::<Vec<String>>();
+            instance_ids.sort();
+            instance_ids.dedup();
+            let mut ips = HashMap::new();
+            let ec2_client = Ec2Client::new_with(http_client()?, credentials, reg);
+            for batch in instance_ids.chunks(DESCRIBE_BATCH) {
+                let filters = vec![InstanceFilter {
+                    name: "instance-id".to_string(),
+                    values: batch.to_vec(),
+                }];
+                // The primary address, not that of some task's ENI
+                for inst in describe_instance_list(&ec2_client, &self.environ, &filters)? {
+                    if let (Some(id), Some(ip)) = (inst.instance_id, inst.private_ip_address) {
+                        ips.insert(id, ip);
+                    }
+                }
+            }
+            for task in tasks.iter_mut() {
+                if let Some(ip) = ips.get(&task.ec2_instance_id) {
+                    task.private_ip = ip.clone();
+                }
+            }
+            Ok(tasks)
+        }
+    }
+
+    fn list_clusters(client: &EcsClient) -> Result<Vec<String>, String> {
+        let mut cluster_arns = Vec::new();
+        let mut next_token = None;
+        loop {
+            let request_input = ListClustersRequest {
+                next_token: next_token,
+                ..Default::default()
+            };
+            let response = match client.list_clusters(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking list_clusters: {:?}", error))
+            };
+            cluster_arns.extend(response.cluster_arns.unwrap_or(Vec::new()));
+            next_token = response.next_token.filter(|token| !token.is_empty());
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok(cluster_arns)
+    }
+
+    // The running tasks of one cluster that are on a container instance
+    fn cluster_tasks(client: &EcsClient, cluster_arn: &String) -> Result<Vec<EcsTaskInfo>, String> {
+        let mut task_arns = Vec::new();
+        let mut next_token = None;
+        loop {
+            let request_input = ListTasksRequest {
+                cluster: Some(cluster_arn.clone()),
+                desired_status: Some("RUNNING".to_string()),
+                next_token: next_token,
+                ..Default::default()
+            };
+            let response = match client.list_tasks(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking list_tasks: {:?}", error))
+            };
+            task_arns.extend(response.task_arns.unwrap_or(Vec::new()));
+            next_token = response.next_token.filter(|token| !token.is_empty());
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        let mut described_tasks = Vec::new();
+        for batch in task_arns.chunks(DESCRIBE_BATCH) {
+            let request_input = DescribeTasksRequest {
+                cluster: Some(cluster_arn.clone()),
+                tasks: batch.to_vec(),
+                ..Default::default()
+            };
+            match client.describe_tasks(request_input).sync() {
+                Ok(response) => described_tasks.extend(response.tasks.unwrap_or(Vec::new())),
+                Err(error) => return Err(format!("Error when invoking describe_tasks: {:?}", error))
+            }
+        }
+
+        let container_instance_arns = described_tasks.iter()
+            .filter_map(|t| t.container_instance_arn.clone())
+            .collect::<Vec<String>>();
+        let mut ec2_ids = HashMap::new();
+        for batch in container_instance_arns.chunks(DESCRIBE_BATCH) {
+            let request_input = DescribeContainerInstancesRequest {
+                cluster: Some(cluster_arn.clone()),
+                container_instances: batch.to_vec(),
+                ..Default::default()
+            };
+            match client.describe_container_instances(request_input).sync() {
+                Ok(response) => {
+                    for ci in response.container_instances.unwrap_or(Vec::new()) {
+                        if let (Some(arn), Some(ec2_id)) = (ci.container_instance_arn, ci.ec_2_instance_id) {
+                            ec2_ids.insert(arn, ec2_id);
+                        }
+                    }
+                },
+                Err(error) => return Err(format!("Error when invoking describe_container_instances: {:?}", error))
+            }
+        }
+
+        Ok(ecs_task_info_list(cluster_arn, described_tasks, &ec2_ids))
+    }
+
+    // The last part of an arn, e.g. the name of a cluster or family:revision
+    // of a task definition
+    fn arn_name(arn: &String) -> String {
+        arn.rsplit('/').next().unwrap_or("").to_string()
+    }
+
+    pub fn ecs_task_info_list(cluster_arn: &String, tasks: Vec<Task>, ec2_ids: &HashMap<String, String>) -> Vec<EcsTaskInfo> {
+        let mut limited_tasks = Vec::new();
+        for task in tasks {
+            let ec2_instance_id = match task.container_instance_arn.as_ref().and_then(|arn| ec2_ids.get(arn)) {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+            // Tasks started by a service are in the group "service:<name>"
+            let service = match task.group {
+                Some(ref group) if group.starts_with("service:") => group["service:".len()..].to_string(),
+                _ => String::new(),
+            };
+            let containers = task.containers
+                .unwrap_or(Vec::new())
+                .into_iter()
+                .map(|c| EcsContainerInfo {
+                    name: c.name.unwrap_or_default(),
+                    runtime_id: c.runtime_id.unwrap_or_default(),
+                })
+                .collect();
+            limited_tasks.push(EcsTaskInfo {
+                cluster: arn_name(cluster_arn),
+                service: service,
+                task_arn: task.task_arn.unwrap_or_default(),
+                task_definition: arn_name(&task.task_definition_arn.unwrap_or_default()),
+                last_status: task.last_status.unwrap_or_default(),
+                containers: containers,
+                ec2_instance_id: ec2_instance_id,
+                private_ip: String::new(),
+                region: String::new(),
+            });
+        }
+        limited_tasks
+    }
+
+    pub fn running_tasks(tasks: Vec<EcsTaskInfo>) -> Vec<EcsTaskInfo> {
+        tasks.into_iter()
+            .filter(|t| t.last_status == "RUNNING" && !t.private_ip.is_empty())
+            .collect::<Vec<EcsTaskInfo>>()
+    }
+
+    pub fn read_without_cache(provider: &dyn TaskProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<EcsTaskInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, EcsTaskInfo::API);
+        cache::read_without_cache(cache_dir, &key, settings, || provider.tasks(aws_account_id, region_name))
+    }
+
+    pub fn read_via_cache(provider: &dyn TaskProvider, cache_dir: &String, region_name: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<EcsTaskInfo> {
+        let key = CacheKey::new(aws_account_id, region_name, EcsTaskInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.tasks(aws_account_id, region_name))
+    }
+
+    pub fn read_regions(provider: Arc<dyn TaskProvider>, scope: &CacheScope) -> Vec<EcsTaskInfo> {
+        let account = scope.account.clone();
+        cache::read_regions(scope, move |region_name| provider.tasks(&account, region_name))
+    }
+}
+
+// DNS records from every hosted zone.  Route53 is a global API, so these
+// are cached once per account under the "global" region instead of per
+// region.  A record can be followed (through CNAMEs and aliases) back to the
+// addresses or load balancers it points at, and from there to instances.
+pub mod route53_records {
+    use rusoto_route53::{Route53, Route53Client, ListHostedZonesRequest, ListResourceRecordSetsRequest, ResourceRecordSet};
+
+    use std::collections::{HashMap, HashSet};
+
+    use config::EnvironConfig;
+    use credentials::{account_session, http_client};
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings, Regional};
+    use ec2_instances::Searchable;
+
+    // Global APIs are signed for, and served from, us-east-1
+    const GLOBAL_API_REGION: &'static str = "us-east-1";
+    // How many CNAMEs/aliases to follow before giving up on a loop
+    const MAX_HOPS: usize = 8;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct RecordInfo {
+        pub zone_id: String,
+        pub zone_name: String,
+        pub private_zone: bool,
+        // Without the trailing dot
+        pub name: String,
+        pub record_type: String,
+        pub values: Vec<String>,
+        // The dns name an alias record points at, if it is one
+        pub alias_target: Option<String>,
+    }
+
+    impl Searchable for RecordInfo {
+        fn field(&self, name: &String) -> Option<&String> {
+            match name.as_str() {
+                "name" => Some(&self.name),
+                _ => None,
+            }
+        }
+    }
+
+    impl CachedResource for RecordInfo {
+        const API: &'static str = "route53_records";
+    }
+
+    // Records are read once for the whole account, so there's no region to keep
+    impl Regional for RecordInfo {
+        fn set_region(&mut self, _region_name: &String) {}
+    }
+
+    pub trait RecordProvider: Send + Sync {
+        fn records(&self, account: &String) -> Result<Vec<RecordInfo>, String>;
+    }
+
+    pub struct Route53Provider {
+        pub environ: EnvironConfig,
+    }
+
+    impl Route53Provider {
+        pub fn from_config(environ: &EnvironConfig) -> Route53Provider {
+            Route53Provider {
+                environ: environ.clone(),
+            }
+        }
+    }
+
+    impl RecordProvider for Route53Provider {
+        fn records(&self, account: &String) -> Result<Vec<RecordInfo>, String> {
+            let (reg, credentials) = account_session(&self.environ, &GLOBAL_API_REGION.to_string(), account)?;
+            let client = Route53Client::new_with(http_client()?, credentials, reg);
+
+            let mut records = Vec::new();
+            let mut marker = None;
+            loop {
+                let request_input = ListHostedZonesRequest {
+                    marker: marker,
+                    ..Default::default()
+                };
+                let response = match client.list_hosted_zones(request_input).sync() {
+                    Ok(response) => response,
+                    Err(error) => return Err(format!("Error when invoking list_hosted_zones: {:?}", error))
+                };
+                for zone in response.hosted_zones {
+                    // Zone ids come back as /hostedzone/<id>
+                    let zone_id = zone.id.trim_start_matches("/hostedzone/").to_string();
+                    let private_zone = zone.config.and_then(|c| c.private_zone).unwrap_or(false);
+                    let zone_name = strip_dot(&zone.name);
+                    for record in zone_records(&client, &zone_id)? {
+                        records.push(record_info(&zone_id, &zone_name, private_zone, record));
+                    }
+                }
+                if !response.is_truncated {
+                    break;
+                }
+                marker = response.next_marker;
+            }
+            Ok(records)
+        }
+    }
+
+    fn zone_records(client: &Route53Client, zone_id: &String) -> Result<Vec<ResourceRecordSet>, String> {
+        let mut record_sets = Vec::new();
+        let mut start = (None, None, None);
+        loop {
+            let request_input = ListResourceRecordSetsRequest {
+                hosted_zone_id: zone_id.clone(),
+                start_record_name: start.0,
+                start_record_type: start.1,
+                start_record_identifier: start.2,
+                ..Default::default()
+            };
+            let response = match client.list_resource_record_sets(request_input).sync() {
+                Ok(response) => response,
+                Err(error) => return Err(format!("Error when invoking list_resource_record_sets: {:?}", error))
+            };
+            record_sets.extend(response.resource_record_sets);
+            if !response.is_truncated {
+                break;
+            }
+            start = (response.next_record_name, response.next_record_type, response.next_record_identifier);
+        }
+        Ok(record_sets)
+    }
+
+    fn strip_dot(name: &String) -> String {
+        name.trim_end_matches('.').to_lowercase()
+    }
+
+    pub fn record_info(zone_id: &String, zone_name: &String, private_zone: bool, record: ResourceRecordSet) -> RecordInfo {
+        RecordInfo {
+            zone_id: zone_id.clone(),
+            zone_name: zone_name.clone(),
+            private_zone: private_zone,
+            name: strip_dot(&record.name),
+            record_type: record.type_,
+            values: record.resource_records
+                .unwrap_or_default()
+                .into_iter()
+                .map(|r| r.value)
+                .collect(),
+            alias_target: record.alias_target.map(|a| strip_dot(&a.dns_name)),
+        }
+    }
+
+    // Follow the records through CNAMEs and aliases to the addresses they
+    // end at (left), and the dns names that aren't in any zone we know of
+    // (right), e.g. the dns name of a load balancer.
+    pub fn resolve_records(records: &Vec<RecordInfo>, all_records: &Vec<RecordInfo>) -> (HashSet<String>, HashSet<String>) {
+        let mut by_name: HashMap<&String, Vec<&RecordInfo>> = HashMap::new();
+        for record in all_records {
+            by_name.entry(&record.name).or_default().push(record);
+        }
+
+        let mut addresses = HashSet::new();
+        let mut external_names = HashSet::new();
+        let mut pending = records.iter().collect::<Vec<&RecordInfo>>();
+        for _ in 0..MAX_HOPS {
+            let mut next = Vec::new();
+            for record in pending {
+                let mut targets = Vec::new();
+                if let Some(ref alias) = record.alias_target {
+                    targets.push(alias.clone());
+                }
+                match record.record_type.as_str() {
+                    "A" | "AAAA" => addresses.extend(record.values.iter().cloned()),
+                    "CNAME" => targets.extend(record.values.iter().map(strip_dot)),
+                    _ => (),
+                }
+                for target in targets {
+                    match by_name.get(&target) {
+                        Some(found) => next.extend(found.iter().cloned()),
+                        None => { external_names.insert(target); },
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            pending = next;
+        }
+        (addresses, external_names)
+    }
+
+    // Load balancer dns names are given to aliases as "dualstack.<name>"
+    pub fn canonical_dns_name(name: &String) -> String {
+        let lower = strip_dot(name);
+        lower.trim_start_matches("dualstack.").to_string()
+    }
+
+    pub fn read_without_cache(provider: &dyn RecordProvider, cache_dir: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<RecordInfo> {
+        let key = CacheKey::global(aws_account_id, RecordInfo::API);
+        cache::read_without_cache(cache_dir, &key, settings, || provider.records(aws_account_id))
+    }
+
+    pub fn read_via_cache(provider: &dyn RecordProvider, cache_dir: &String, aws_account_id: &String, settings: &CacheSettings) -> Vec<RecordInfo> {
+        let key = CacheKey::global(aws_account_id, RecordInfo::API);
+        cache::read_via_cache(cache_dir, &key, settings, || provider.records(aws_account_id))
+    }
+
+    // The account's records, through the cache unless the scope bypasses it.
+    // Its regions don't matter, records are global.
+    pub fn read_records(provider: &dyn RecordProvider, scope: &CacheScope) -> Vec<RecordInfo> {
+        if scope.bypass_cache {
+            read_without_cache(provider, &scope.cache_dir, &scope.account, &scope.settings)
+        } else {
+            read_via_cache(provider, &scope.cache_dir, &scope.account, &scope.settings)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashSet;
+        use super::*;
+
+        fn record(name: &str, record_type: &str, values: Vec<&str>, alias_target: Option<&str>) -> RecordInfo {
+            RecordInfo {
+                zone_id: "Z1".to_string(),
+                zone_name: "example.com".to_string(),
+                private_zone: false,
+                name: name.to_string(),
+                record_type: record_type.to_string(),
+                values: values.into_iter().map(|v| v.to_string()).collect(),
+                alias_target: alias_target.map(|a| a.to_string()),
+            }
+        }
+
+        fn set(values: Vec<&str>) -> HashSet<String> {
+            values.into_iter().map(|v| v.to_string()).collect()
+        }
+
+        #[test]
+        fn records_are_followed_to_addresses_and_outside_names() {
+            let all_records = vec![
+                record("web.example.com", "CNAME", vec!["web-1.example.com."], None),
+                record("web-1.example.com", "A", vec!["10.0.0.1"], None),
+                record("api.example.com", "A", Vec::new(), Some("dualstack.api-lb-123.us-east-1.elb.amazonaws.com")),
+            ];
+            let (addresses, external_names) = resolve_records(&vec![all_records[0].clone()], &all_records);
+            assert_eq!(addresses, set(vec!["10.0.0.1"]));
+            assert!(external_names.is_empty());
+
+            let (addresses, external_names) = resolve_records(&vec![all_records[2].clone()], &all_records);
+            assert!(addresses.is_empty());
+            assert_eq!(external_names, set(vec!["dualstack.api-lb-123.us-east-1.elb.amazonaws.com"]));
+        }
+
+        #[test]
+        fn cname_loops_end() {
+            let all_records = vec![
+                record("a.example.com", "CNAME", vec!["b.example.com"], None),
+                record("b.example.com", "CNAME", vec!["a.example.com"], None),
+            ];
+            let (addresses, external_names) = resolve_records(&all_records, &all_records);
+            assert!(addresses.is_empty());
+            assert!(external_names.is_empty());
+        }
+    }
+}
+
+// What's behind acache: finding the cache files in a data dir, and reading,
+// refreshing, deleting or warming them without knowing ahead of time which
+// API each one belongs to.
+pub mod cache_admin {
+    use std::fs::{read_dir, remove_file};
+    use std::io;
+    use std::sync::Arc;
+
+    use chrono::prelude::*;
+
+    use config::EnvironConfig;
+    use cache;
+    use cache::{CacheKey, CacheScope, CachedResource, CacheSettings};
+    use cache_files::{ALL_ENCODINGS, CacheEncoding};
+    use ec2_instances;
+    use ec2_instances::{AshufInfo, Ec2Provider};
+    use rds_instances;
+    use rds_instances::{RdsInfo, RdsProvider};
+    use elasticache_nodes;
+    use elasticache_nodes::{CacheNodeInfo, ElastiCacheProvider};
+    use load_balancers;
+    use load_balancers::{LoadBalancerInfo, ElbProvider};
+    use ecs_tasks;
+    use ecs_tasks::{EcsTaskInfo, EcsProvider};
+    use route53_records;
+    use route53_records::{RecordInfo, Route53Provider};
+
+    // Every API that has a cache
+    pub const APIS: [&'static str; 6] = [AshufInfo::API, RdsInfo::API, CacheNodeInfo::API, LoadBalancerInfo::API, EcsTaskInfo::API, RecordInfo::API];
+
+    // One cache file, and what could be read from it
+    #[derive(Clone, Debug)]
+    pub struct CacheEntry {
+        pub key: CacheKey,
+        pub encoding: CacheEncoding,
+        // Either when it was written and how many resources are in it, or
+        // why it couldn't be read
+        pub contents: Result<(DateTime<Utc>, usize), String>,
+    }
+
+    // The API named in a cache file's name, as the &'static str its key uses
+    pub fn api_named(name: &str) -> Option<&'static str> {
+        APIS.iter().find(|api| **api == name).copied()
+    }
+
+    // {account}_{region}_{api}.{encoding}, or None for anything else in the
+    // data dir (the config, lock files, the raald socket, ...)
+    pub fn parse_file_name(file_name: &str) -> Option<(CacheKey, CacheEncoding)> {
+        let (encoding, stem) = ALL_ENCODINGS.iter()
+            .filter_map(|encoding| {
+                let suffix = format!(".{}", encoding.extension());
+                if file_name.ends_with(&suffix) {
+                    Some((*encoding, &file_name[..file_name.len() - suffix.len()]))
+                } else {
+                    None
+                }
+            })
+            // json.gz also ends with .gz, but never with .json, so at most one matches
+            .next()?;
+        let api = APIS.iter().find(|api| stem.ends_with(&format!("_{}", api)))?;
+        let account_region = &stem[..stem.len() - api.len() - 1];
+        let split = account_region.find('_')?;
+        let (account, region_name) = (&account_region[..split], &account_region[split + 1..]);
+        if account.is_empty() || region_name.is_empty() {
+            return None;
+        }
+        Some((CacheKey::new(&account.to_string(), &region_name.to_string(), api), encoding))
+    }
+
+    fn contents_of<T: CachedResource>(cache_dir: &String, key: &CacheKey) -> Result<(DateTime<Utc>, usize), String> {
+        cache::read_saved::<T>(cache_dir, key)
+            .map(|envelope| (envelope.written_time, envelope.data.len()))
+            .map_err(|err| err.to_string())
+    }
+
+    fn unknown_api(api: &str) -> String {
+        format!("There's no cache for {}, expected one of {}", api, APIS.join(", "))
+    }
+
+    pub fn read_contents(cache_dir: &String, key: &CacheKey) -> Result<(DateTime<Utc>, usize), String> {
+        match key.api {
+            AshufInfo::API => contents_of::<AshufInfo>(cache_dir, key),
+            RdsInfo::API => contents_of::<RdsInfo>(cache_dir, key),
+            CacheNodeInfo::API => contents_of::<CacheNodeInfo>(cache_dir, key),
+            LoadBalancerInfo::API => contents_of::<LoadBalancerInfo>(cache_dir, key),
+            EcsTaskInfo::API => contents_of::<EcsTaskInfo>(cache_dir, key),
+            RecordInfo::API => contents_of::<RecordInfo>(cache_dir, key),
+            other => Err(unknown_api(other)),
+        }
+    }
+
+    // Every cache file in the data dir, sorted by account, region and API
+    pub fn list_entries(cache_dir: &String) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        for dir_entry in read_dir(cache_dir)? {
+            let file_name = dir_entry?.file_name();
+            if let Some((key, encoding)) = file_name.to_str().and_then(parse_file_name) {
+                let contents = read_contents(cache_dir, &key);
+                entries.push(CacheEntry {
+                    key: key,
+                    encoding: encoding,
+                    contents: contents,
+                });
+            }
+        }
+        entries.sort_by(|a, b| (&a.key.account, &a.key.region, a.key.api).cmp(&(&b.key.account, &b.key.region, b.key.api)));
+        Ok(entries)
+    }
+
+    // Remove the cache file, in whatever encodings it's in.  The lock file
+    // stays, since someone may be holding it.
+    pub fn delete_entry(cache_dir: &String, key: &CacheKey) -> io::Result<()> {
+        let base_pathname = key.base_pathname(cache_dir);
+        for encoding in ALL_ENCODINGS.iter() {
+            match remove_file(format!("{}.{}", base_pathname, encoding.extension())) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                other => other?,
+            }
+        }
+        Ok(())
+    }
+
+    // Go to the API for this entry now, whatever its age.  Returns how many
+    // resources were found.
+    pub fn refresh_entry(cache_dir: &String, environ: &EnvironConfig, key: &CacheKey, settings: &CacheSettings) -> Result<usize, String> {
+        let (account, region_name) = (&key.account, &key.region);
+        let found = match key.api {
+            AshufInfo::API => ec2_instances::read_without_cache(&Ec2Provider::from_config(environ), cache_dir, region_name, account, &Vec::new(), settings).len(),
+            RdsInfo::API => rds_instances::read_without_cache(&RdsProvider::from_config(environ), cache_dir, region_name, account, settings).len(),
+            CacheNodeInfo::API => elasticache_nodes::read_without_cache(&ElastiCacheProvider::from_config(environ), cache_dir, region_name, account, settings).len(),
+            LoadBalancerInfo::API => load_balancers::read_without_cache(&ElbProvider::from_config(environ), cache_dir, region_name, account, settings).len(),
+            EcsTaskInfo::API => ecs_tasks::read_without_cache(&EcsProvider::from_config(environ), cache_dir, region_name, account, settings).len(),
+            RecordInfo::API => route53_records::read_without_cache(&Route53Provider::from_config(environ), cache_dir, account, settings).len(),
+            other => return Err(unknown_api(other)),
+        };
+        Ok(found)
+    }
+
+    // Bring one API's caches for every region of an environment up to date,
+    // leaving alone the ones that are still fresh.  Returns how many
+    // resources there are.
+    pub fn warm_environment(cache_dir: &String, environ: &EnvironConfig, api: &'static str, settings: &CacheSettings) -> Result<usize, String> {
+        let scope = CacheScope {
+            cache_dir: cache_dir.clone(),
+            account: environ.account_id.clone(),
+            regions: environ.all_regions(),
+            bypass_cache: false,
+            settings: settings.clone(),
+        };
+        let found = match api {
+            AshufInfo::API => ec2_instances::read_regions(Arc::new(Ec2Provider::from_config(environ)), &scope, &Vec::new()).len(),
+            RdsInfo::API => rds_instances::read_regions(Arc::new(RdsProvider::from_config(environ)), &scope).len(),
+            CacheNodeInfo::API => elasticache_nodes::read_regions(Arc::new(ElastiCacheProvider::from_config(environ)), &scope).len(),
+            LoadBalancerInfo::API => load_balancers::read_regions(Arc::new(ElbProvider::from_config(environ)), &scope).len(),
+            EcsTaskInfo::API => ecs_tasks::read_regions(Arc::new(EcsProvider::from_config(environ)), &scope).len(),
+            RecordInfo::API => route53_records::read_records(&Route53Provider::from_config(environ), &scope).len(),
+            other => return Err(unknown_api(other)),
+        };
+        Ok(found)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn cache_file_names() {
+            let (key, encoding) = parse_file_name("123_us-east-1_ec2_instances.json").unwrap();
+            assert_eq!((key.account.as_str(), key.region.as_str(), key.api), ("123", "us-east-1", "ec2_instances"));
+            assert_eq!(encoding, CacheEncoding::Json);
+
+            let (key, encoding) = parse_file_name("123_global_route53_records.json.gz").unwrap();
+            assert_eq!((key.region.as_str(), key.api), ("global", "route53_records"));
+            assert_eq!(encoding, CacheEncoding::JsonGz);
+
+            let (key, encoding) = parse_file_name("123_eu-west-1_ecs_tasks.bincode").unwrap();
+            assert_eq!((key.region.as_str(), key.api), ("eu-west-1", "ecs_tasks"));
+            assert_eq!(encoding, CacheEncoding::Bincode);
+        }
+
+        #[test]
+        fn other_files_are_not_caches() {
+            assert!(parse_file_name("123_us-east-1_ec2_instances.lock").is_none());
+            assert!(parse_file_name("123_us-east-1_ec2_instances.json.4242.tmp").is_none());
+            assert!(parse_file_name("config.toml").is_none());
+            assert!(parse_file_name("_us-east-1_ec2_instances.json").is_none());
+            assert!(parse_file_name("123_us-east-1_s3_buckets.json").is_none());
+        }
+
+        #[test]
+        fn unknown_apis_are_errors() {
+            let key = CacheKey::new(&"123".to_string(), &"us-east-1".to_string(), "s3_buckets");
+            assert_eq!(read_contents(&"/nonexistent".to_string(), &key).unwrap_err(),
+                       "There's no cache for s3_buckets, expected one of ec2_instances, rds_instances, elasticache_nodes, load_balancers, ecs_tasks, route53_records");
+        }
+    }
+}
+
+// What changed between two lists of instances, e.g. two snapshots of the
+// same cache (see cache::save_snapshot) or two polls of aal --watch.
+pub mod history {
+    use std::collections::{BTreeSet, HashMap};
+
+    use chrono::prelude::*;
+
+    use cache::{CacheKey, Regional, list_snapshots, read_saved, read_saved_from};
+    use ec2_instances::AshufInfo;
+
+    // What happened to one instance between two lists of instances.  An
+    // instance that changed in more than one way shows up more than once.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(tag = "change", rename_all = "snake_case")]
+    pub enum InstanceChange {
+        Launched { instance: AshufInfo },
+        Terminated { instance: AshufInfo },
+        StateChanged { instance: AshufInfo, from: String, to: String },
+        IpChanged { instance: AshufInfo, from: Vec<String>, to: Vec<String> },
+        TagsChanged {
+            instance: AshufInfo,
+            added: HashMap<String, String>,
+            removed: HashMap<String, String>,
+            // tag -> (before, after)
+            changed: HashMap<String, (String, String)>,
+        },
+    }
+
+    impl InstanceChange {
+        // The instance as it is afterwards, or as it was last seen
+        pub fn instance(&self) -> &AshufInfo {
+            match *self {
+                InstanceChange::Launched { ref instance } |
+                InstanceChange::Terminated { ref instance } |
+                InstanceChange::StateChanged { ref instance, .. } |
+                InstanceChange::IpChanged { ref instance, .. } |
+                InstanceChange::TagsChanged { ref instance, .. } => instance,
+            }
+        }
+
+        // One line for people, e.g.
+        //   - web-2 i-0abc us-east-1 (terminated)
+        pub fn describe(&self) -> String {
+            let instance = self.instance();
+            let name = instance.tags.get("Name").map_or("-", |name| name.as_str());
+            let who = format!("{} {} {}", name, instance.instance_id, instance.region);
+            match *self {
+                InstanceChange::Launched { .. } =>
+                    format!("+ {} launched {}", who, instance.launch_time),
+                InstanceChange::Terminated { .. } =>
+                    format!("- {} gone (was {})", who, instance.state_name),
+                InstanceChange::StateChanged { ref from, ref to, .. } =>
+                    format!("~ {} state {} -> {}", who, from, to),
+                InstanceChange::IpChanged { ref from, ref to, .. } =>
+                    format!("~ {} addresses {} -> {}", who, from.join(","), to.join(",")),
+                InstanceChange::TagsChanged { ref added, ref removed, ref changed, .. } => {
+                    let mut parts = Vec::new();
+                    for (tag, value) in sorted(added) {
+                        parts.push(format!("+{}={}", tag, value));
+                    }
+                    for (tag, value) in sorted(removed) {
+                        parts.push(format!("-{}={}", tag, value));
+                    }
+                    for (tag, (before, after)) in sorted(changed) {
+                        parts.push(format!("{}: {} -> {}", tag, before, after));
+                    }
+                    format!("~ {} tags {}", who, parts.join(" "))
+                }
+            }
+        }
+    }
+
+    // The instances in the current cache (0) or in one of the snapshots
+    // before it (1 is the one it replaced), and when they were written
+    pub fn snapshot_instances(cache_dir: &String, key: &CacheKey, index: usize) -> Result<(DateTime<Utc>, Vec<AshufInfo>), String> {
+        let envelope = if index == 0 {
+            read_saved::<AshufInfo>(cache_dir, key)
+        } else {
+            let snapshots = list_snapshots(cache_dir, key).map_err(|err| err.to_string())?;
+            match snapshots.get(index - 1) {
+                Some(snapshot) => read_saved_from::<AshufInfo>(snapshot),
+                None => return Err(format!("there are only {} snapshots", snapshots.len())),
+            }
+        };
+        let mut envelope = envelope.map_err(|err| err.to_string())?;
+        // Only read_regions fills the region in, so the cache may not have it
+        for inst in envelope.data.iter_mut() {
+            inst.set_region(&key.region);
+        }
+        Ok((envelope.written_time, envelope.data))
+    }
+
+    fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+        let mut pairs: Vec<(&String, &V)> = map.iter().collect();
+        pairs.sort_by(|a, b| a.0.cmp(b.0));
+        pairs
+    }
+
+    // Every address of the instance, in order.  Which order the API listed
+    // them in doesn't matter, and older caches may have empty ones.
+    fn addresses(instance: &AshufInfo) -> BTreeSet<String> {
+        instance.private_ip_addresses.iter()
+            .chain(instance.public_ip_addresses.iter())
+            .filter(|addr| !addr.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    // Everything that changed from `before` to `after`, matching instances up
+    // by id.  Terminated instances linger in the API for a while, so one
+    // usually shows up as a state change first and as gone later.
+    pub fn diff_instances(before: &Vec<AshufInfo>, after: &Vec<AshufInfo>) -> Vec<InstanceChange> {
+        let before_by_id: HashMap<&String, &AshufInfo> = before.iter().map(|i| (&i.instance_id, i)).collect();
+        let after_by_id: HashMap<&String, &AshufInfo> = after.iter().map(|i| (&i.instance_id, i)).collect();
+        let mut changes = Vec::new();
+
+        for old in before {
+            if !after_by_id.contains_key(&old.instance_id) {
+                changes.push(InstanceChange::Terminated { instance: old.clone() });
+            }
+        }
+        for new in after {
+            let old = match before_by_id.get(&new.instance_id) {
+                Some(old) => old,
+                None => {
+                    changes.push(InstanceChange::Launched { instance: new.clone() });
+                    continue;
+                }
+            };
+            if old.state_name != new.state_name {
+                changes.push(InstanceChange::StateChanged {
+                    instance: new.clone(),
+                    from: old.state_name.clone(),
+                    to: new.state_name.clone(),
+                });
+            }
+            let (old_addresses, new_addresses) = (addresses(old), addresses(new));
+            if old_addresses != new_addresses {
+                changes.push(InstanceChange::IpChanged {
+                    instance: new.clone(),
+                    from: old_addresses.into_iter().collect(),
+                    to: new_addresses.into_iter().collect(),
+                });
+            }
+            if old.tags != new.tags {
+                let added = new.tags.iter()
+                    .filter(|&(tag, _)| !old.tags.contains_key(tag))
+                    .map(|(tag, value)| (tag.clone(), value.clone()))
+                    .collect();
+                let removed = old.tags.iter()
+                    .filter(|&(tag, _)| !new.tags.contains_key(tag))
+                    .map(|(tag, value)| (tag.clone(), value.clone()))
+                    .collect();
+                let changed = new.tags.iter()
+                    .filter_map(|(tag, value)| match old.tags.get(tag) {
+                        Some(old_value) if old_value != value => Some((tag.clone(), (old_value.clone(), value.clone()))),
+                        _ => None,
+                    })
+                    .collect();
+                changes.push(InstanceChange::TagsChanged {
+                    instance: new.clone(),
+                    added: added,
+                    removed: removed,
+                    changed: changed,
+                });
+            }
+        }
+        changes
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ec2_instances::tests;
+
+        fn instance(id: &str, state_name: &str, ip: &str, tier: &str) -> AshufInfo {
+            let mut inst = tests::instance(id, "us-east-1a");
+            inst.state_name = state_name.to_string();
+            inst.private_ip_addresses = vec![ip.to_string()];
+            inst.tags.insert("Tier".to_string(), tier.to_string());
+            inst.region = "us-east-1".to_string();
+            inst
+        }
+
+        #[test]
+        fn nothing_changed() {
+            let instances = vec![instance("i-1", "running", "10.0.0.1", "web")];
+            assert!(diff_instances(&instances, &instances).is_empty());
+        }
+
+        #[test]
+        fn every_kind_of_change() {
+            let before = vec![
+                instance("i-1", "running", "10.0.0.1", "web"),
+                instance("i-2", "running", "10.0.0.2", "web"),
+                instance("i-3", "running", "10.0.0.3", "web"),
+                instance("i-4", "running", "10.0.0.4", "web"),
+            ];
+            let after = vec![
+                instance("i-2", "shutting-down", "10.0.0.2", "web"),
+                instance("i-3", "running", "10.0.0.33", "web"),
+                instance("i-4", "running", "10.0.0.4", "api"),
+                instance("i-5", "pending", "10.0.0.5", "web"),
+            ];
+            let changes = diff_instances(&before, &after);
+            assert_eq!(changes.len(), 5);
+            let described = changes.iter().map(|c| (c.instance().instance_id.clone(), c.describe())).collect::<Vec<_>>();
+            assert!(described.contains(&("i-1".to_string(), "- web-i-1 i-1 us-east-1 gone (was running)".to_string())));
+            assert!(described.contains(&("i-2".to_string(), "~ web-i-2 i-2 us-east-1 state running -> shutting-down".to_string())));
+            assert!(described.contains(&("i-3".to_string(), "~ web-i-3 i-3 us-east-1 addresses 10.0.0.3 -> 10.0.0.33".to_string())));
+            assert!(described.contains(&("i-4".to_string(), "~ web-i-4 i-4 us-east-1 tags Tier: web -> api".to_string())));
+            assert!(described.contains(&("i-5".to_string(), "+ web-i-5 i-5 us-east-1 launched 2019-06-01T12:00:00.000Z".to_string())));
+        }
+
+        #[test]
+        fn added_and_removed_tags() {
+            let before = instance("i-1", "running", "10.0.0.1", "web");
+            let mut after = before.clone();
+            after.tags.remove("Tier");
+            after.tags.insert("Owner".to_string(), "team-1".to_string());
+            match diff_instances(&vec![before], &vec![after]).pop() {
+                Some(InstanceChange::TagsChanged { added, removed, changed, .. }) => {
+                    assert_eq!(added.get("Owner"), Some(&"team-1".to_string()));
+                    assert_eq!(removed.get("Tier"), Some(&"web".to_string()));
+                    assert!(changed.is_empty());
+                },
+                other => panic!("Expected a tag change, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn reordered_addresses_are_not_a_change() {
+            let mut before = instance("i-1", "running", "10.0.0.1", "web");
+            before.private_ip_addresses.push("10.0.0.2".to_string());
+            let mut after = before.clone();
+            after.private_ip_addresses.reverse();
+            // Older caches have an empty public address when there is none
+            before.public_ip_addresses.push(String::new());
+            assert!(diff_instances(&vec![before], &vec![after]).is_empty());
+        }
+    }
+}
+
+// raald keeps every environment's instances in memory and answers pattern
+// queries over a unix socket in the data dir, so that aal and ashuf don't
+// have to parse the cache (or wait for the API) every time they run.  The
+// protocol is one line of json each way.  When raald isn't running, or
+// can't answer, the clients go through the cache as usual.
+pub mod daemon {
+    use std::collections::HashMap;
+    use std::fs::{Permissions, remove_file, set_permissions};
+    use std::io;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use regex::Regex;
+    use serde_json;
+
+    use config::EnvironConfig;
+    use cache::{CacheScope, CacheSettings, CachePolicy};
+    use ec2_instances::{AshufInfo, Ec2Provider, InstanceProvider, instances_matching_regex, read_regions};
+
+    pub const SOCKET_NAME: &'static str = "raald.sock";
+    // How long a client waits for an answer before giving up on raald
+    const CLIENT_TIMEOUT_SECS: u64 = 2;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct DaemonQuery {
+        pub env_name: String,
+        pub regions: Vec<String>,
+        pub pattern: String,
+        pub tags: Vec<String>,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub enum DaemonReply {
+        Matches(Vec<AshufInfo>),
+        Error(String),
+    }
+
+    // environment name -> region -> instances
+    pub type DaemonData = Arc<RwLock<HashMap<String, HashMap<String, Vec<AshufInfo>>>>>;
+
+    pub fn socket_path(data_dir: &String) -> String {
+        format!("{}/{}", data_dir, SOCKET_NAME)
+    }
+
+    // Ask raald, if it's there.  None means the caller should read the
+    // cache itself.
+    pub fn query(data_dir: &String, daemon_query: &DaemonQuery) -> Option<Vec<AshufInfo>> {
+        match ask(data_dir, daemon_query) {
+            Ok(DaemonReply::Matches(instances)) => Some(instances),
+            Ok(DaemonReply::Error(error)) => {
+                eprintln!("raald couldn't answer: {}", error);
+                None
+            },
+            // Most likely raald isn't running, which is fine
+            Err(_) => None,
+        }
+    }
+
+    fn ask(data_dir: &String, daemon_query: &DaemonQuery) -> io::Result<DaemonReply> {
+        let mut stream = UnixStream::connect(socket_path(data_dir))?;
+        stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+        let mut request = serde_json::to_string(daemon_query)?;
+        request.push('\n');
+        stream.write_all(request.as_bytes())?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    // Keep one environment's instances current, forever.  The cache files
+    // are still written, so the clients have something when raald stops.
+    pub fn refresh_environment(data: DaemonData, env_name: String, environ: EnvironConfig, data_dir: String, settings: CacheSettings, interval: i64) {
+        let regions = environ.all_regions();
+        let provider: Arc<dyn InstanceProvider> = Arc::new(Ec2Provider::from_config(&environ));
+        // Anything younger than the interval is as good as what raald would get
+        let settings = CacheSettings {
+            ttl: interval,
+            policy: CachePolicy::Ttl,
+            ..settings
+        };
+        let scope = CacheScope {
+            cache_dir: data_dir,
+            account: environ.account_id.clone(),
+            regions: regions.clone(),
+            bypass_cache: false,
+            settings: settings,
+        };
+        loop {
+            let instances = read_regions(provider.clone(), &scope, &Vec::new());
+            let mut by_region: HashMap<String, Vec<AshufInfo>> = regions.iter()
+                .map(|region_name| (region_name.clone(), Vec::new()))
+                .collect();
+            for inst in instances {
+                by_region.entry(inst.region.clone()).or_default().push(inst);
+            }
+            println!("Refreshed {}: {} instances", env_name, by_region.values().map(|v| v.len()).sum::<usize>());
+            data.write().unwrap().insert(env_name.clone(), by_region);
+            thread::sleep(Duration::from_secs(interval as u64));
+        }
+    }
+
+    pub fn answer(data: &DaemonData, daemon_query: DaemonQuery) -> DaemonReply {
+        // instances_matching_regex would panic on it, and take the client's
+        // thread with it
+        if let Err(err) = Regex::new(&daemon_query.pattern) {
+            return DaemonReply::Error(format!("Couldn't use the pattern {}: {}", daemon_query.pattern, err));
+        }
+        let environments = data.read().unwrap();
+        let by_region = match environments.get(&daemon_query.env_name) {
+            Some(by_region) => by_region,
+            None => return DaemonReply::Error(format!("{} hasn't been loaded", daemon_query.env_name)),
+        };
+        let mut instances = Vec::new();
+        for region_name in &daemon_query.regions {
+            match by_region.get(region_name) {
+                Some(region_instances) => instances.extend(region_instances.iter().cloned()),
+                None => return DaemonReply::Error(format!("{} isn't configured for {}", region_name, daemon_query.env_name)),
+            }
+        }
+        DaemonReply::Matches(instances_matching_regex(daemon_query.pattern, daemon_query.tags, instances))
+    }
+
+    fn handle_client(data: DaemonData, stream: UnixStream) -> io::Result<()> {
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let reply = match serde_json::from_str::<DaemonQuery>(&request) {
+            Ok(daemon_query) => answer(&data, daemon_query),
+            Err(err) => DaemonReply::Error(format!("Couldn't parse the query: {}", err)),
+        };
+        let mut reply_line = serde_json::to_string(&reply)?;
+        reply_line.push('\n');
+        (&stream).write_all(reply_line.as_bytes())
+    }
+
+    // Listen on the socket in data_dir, answering each client on its own
+    // thread.  A socket left behind by a raald that died is replaced, but
+    // one that's answering means raald is already running.
+    pub fn serve(data: DaemonData, data_dir: &String) -> io::Result<()> {
+        let pathname = socket_path(data_dir);
+        if Path::new(&pathname).exists() {
+            if UnixStream::connect(&pathname).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("raald is already listening on {}", pathname)));
+            }
+            remove_file(&pathname)?;
+        }
+        let listener = UnixListener::bind(&pathname)?;
+        // Everyone sharing the data dir may use it
+        set_permissions(&pathname, Permissions::from_mode(0o660))?;
+        println!("Listening on {}", pathname);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let data = data.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = handle_client(data, stream) {
+                            eprintln!("Couldn't answer a client: {}", err);
+                        }
+                    });
+                },
+                Err(err) => eprintln!("Couldn't accept a client: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ec2_instances::tests::instance;
+
+        fn loaded() -> DaemonData {
+            let mut by_region = HashMap::new();
+            by_region.insert("us-east-1".to_string(), vec![instance("i-1", "us-east-1a")]);
+            let mut environments = HashMap::new();
+            environments.insert("prod".to_string(), by_region);
+            Arc::new(RwLock::new(environments))
+        }
+
+        fn query(env_name: &str, region_name: &str, pattern: &str) -> DaemonQuery {
+            DaemonQuery {
+                env_name: env_name.to_string(),
+                regions: vec![region_name.to_string()],
+                pattern: pattern.to_string(),
+                tags: vec!["Name".to_string()],
+            }
+        }
+
+        fn error_of(reply: DaemonReply) -> String {
+            match reply {
+                DaemonReply::Error(err) => err,
+                DaemonReply::Matches(matches) => panic!("expected an error, got {:?}", matches),
+            }
+        }
+
+        #[test]
+        fn matches_come_from_what_is_loaded() {
+            match answer(&loaded(), query("prod", "us-east-1", "web")) {
+                DaemonReply::Matches(matches) => assert_eq!(matches[0].instance_id, "i-1"),
+                DaemonReply::Error(err) => panic!("{}", err),
+            }
+        }
+
+        #[test]
+        fn unknown_environments_and_regions_are_errors() {
+            assert_eq!(error_of(answer(&loaded(), query("staging", "us-east-1", "web"))), "staging hasn't been loaded");
+            assert_eq!(error_of(answer(&loaded(), query("prod", "eu-west-1", "web"))), "eu-west-1 isn't configured for prod");
+        }
+
+        #[test]
+        fn bad_patterns_are_errors() {
+            assert!(error_of(answer(&loaded(), query("prod", "us-east-1", "web-("))).starts_with("Couldn't use the pattern web-("));
+        }
+    }
+}
+
+pub mod launch {
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    // Split a command template like "mysql -h {host} -P {port}" into
+    // arguments, and fill in each {name} from the values.
+    pub fn fill_template(template: &String, values: &HashMap<String, String>) -> Vec<String> {
+        template.split_whitespace()
+            .map(|word| {
+                let mut arg = word.to_string();
+                for (name, value) in values {
+                    arg = arg.replace(&format!("{{{}}}", name), value);
+                }
+                arg
+            })
+            .collect()
+    }
+
+    // Replace this process with the command.  This only returns if
+    // that couldn't be done.
+    pub fn exec_args(args: &Vec<String>) -> io::Error {
+        if args.is_empty() {
+            return io::Error::new(io::ErrorKind::InvalidInput, "the command is empty");
+        }
+        Command::new(&args[0])
+            .args(&args[1..])
+            .exec()
     }
 }